@@ -0,0 +1,366 @@
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use wast::core::{FuncKind, ItemKind, ModuleField};
+use wast::parser::{parse, ParseBuffer};
+use wast::Wat;
+
+use crate::chop_up::{
+    is_nosplit, transform_wat, InstructionType, MemoryInstructionType, ModuleContext, SkipSafe, SplitOptions,
+    TransformOutput, IGNORE_FUNC_PREFIX,
+};
+use crate::extract_module_fields;
+
+/// Format an analysis is written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Normal,
+    CSV,
+    JSON,
+}
+
+/// Instruction counts of a module, along with how its functions are split
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct AnalysisReport {
+    /// Path of the analyzed file, if the module was read from one
+    pub file: Option<String>,
+    /// Size of the module text in bytes
+    pub size: usize,
+    /// Instructions of all transaction functions, ignored functions are left out
+    pub instruction_count: usize,
+    pub load_count: usize,
+    pub store_count: usize,
+    /// Every function defined in the module, in the order they are defined
+    pub functions: Vec<FunctionAnalysis>,
+    /// Why the module could not be split, leaving the split figures of every function at 0
+    pub split_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FunctionAnalysis {
+    pub name: String,
+    pub instruction_count: usize,
+    pub load_count: usize,
+    pub store_count: usize,
+    /// Left as it is by the transformation, either by the `__` prefix or a `@chop:nosplit` annotation
+    pub ignored: bool,
+    /// Suspend points when splitting at every memory access
+    pub split_points: usize,
+    /// Suspend points when splitting with `--skip-safe`
+    pub skip_safe_split_points: usize,
+    /// The most microtransactions a single run of the function is split into
+    pub max_chain_length: usize,
+    /// Bytes of state past the user defined state saved by each split, in table order
+    pub saved_state: Vec<usize>,
+}
+
+impl AnalysisReport {
+    pub fn memory_instruction_count(&self) -> usize {
+        self.load_count + self.store_count
+    }
+
+    pub fn normal_instruction_count(&self) -> usize {
+        self.instruction_count - self.memory_instruction_count()
+    }
+
+    /// Functions split by the transformation
+    pub fn transactions(&self) -> impl Iterator<Item = &FunctionAnalysis> {
+        self.functions.iter().filter(|function| !function.ignored)
+    }
+
+    pub fn write(&self, format: OutputFormat, output: &mut dyn Write) -> Result<()> {
+        match format {
+            OutputFormat::Normal => self.write_normal(output),
+            OutputFormat::CSV => write_reports(std::slice::from_ref(self), format, CsvRows::File, false, output),
+            OutputFormat::JSON => {
+                serde_json::to_writer_pretty(&mut *output, self)?;
+                writeln!(output)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn write_normal(&self, output: &mut dyn Write) -> Result<()> {
+        writeln!(output, "\
+Analysis for file: {file}
+Total size = {size}
+Total instructions = {instruction_count}
+  Of which:
+  Normal instructions = {normal_instruction_count}
+  Memory instructions = {memory_instruction_count}
+    Of which:
+    Load instructions  = {load_count}
+    Store instructions = {store_count}",
+                 file = self.file_name(),
+                 size = self.size,
+                 instruction_count = self.instruction_count,
+                 normal_instruction_count = self.normal_instruction_count(),
+                 memory_instruction_count = self.memory_instruction_count(),
+                 load_count = self.load_count,
+                 store_count = self.store_count)?;
+        if let Some(err) = &self.split_error {
+            writeln!(output, "Splitting failed: {err}")?;
+        }
+        writeln!(output, "\
+Functions:
+  {:<24} {:>12} {:>6} {:>6} {:>12} {:>7}",
+                 "name", "instructions", "loads", "stores", "split points", "ignored")?;
+        for function in &self.functions {
+            writeln!(output, "  {:<24} {:>12} {:>6} {:>6} {:>12} {:>7}",
+                     function.name,
+                     function.instruction_count,
+                     function.load_count,
+                     function.store_count,
+                     function.split_points,
+                     yes_no(function.ignored))?;
+        }
+        for transaction in self.transactions() {
+            writeln!(output, "\
+Transaction ${name}
+  Split points = {split_points}
+  Split points with --skip-safe = {skip_safe_split_points}
+  Max microtransaction chain length = {max_chain_length}
+  Saved state per split = [{saved_state}]",
+                     name = transaction.name,
+                     split_points = transaction.split_points,
+                     skip_safe_split_points = transaction.skip_safe_split_points,
+                     max_chain_length = transaction.max_chain_length,
+                     saved_state = transaction.saved_state.iter().map(|bytes| bytes.to_string()).collect::<Vec<String>>().join(", "))?;
+        }
+        Ok(())
+    }
+
+    fn file_name(&self) -> &str {
+        self.file.as_deref().unwrap_or("-")
+    }
+
+    fn write_csv_row(&self, output: &mut dyn Write) -> Result<()> {
+        let split_points = self.transactions().map(|transaction| transaction.split_points).sum::<usize>();
+        let skip_safe_split_points = self
+            .transactions()
+            .map(|transaction| transaction.skip_safe_split_points)
+            .sum::<usize>();
+        let max_chain_length = self.transactions().map(|transaction| transaction.max_chain_length).max().unwrap_or(0);
+        let max_saved_state = self.transactions().map(|transaction| transaction.max_saved_state()).max().unwrap_or(0);
+        writeln!(output, "\
+{file},{size},{instruction_count},{normal_instruction_count},{memory_instruction_count},{load_count},{store_count},{split_points},{skip_safe_split_points},{max_chain_length},{max_saved_state},{split_error}",
+                 file = self.file_name(),
+                 split_error = csv_field(self.split_error.as_deref().unwrap_or_default()),
+                 size = self.size,
+                 instruction_count = self.instruction_count,
+                 normal_instruction_count = self.normal_instruction_count(),
+                 memory_instruction_count = self.memory_instruction_count(),
+                 load_count = self.load_count,
+                 store_count = self.store_count)?;
+        Ok(())
+    }
+
+}
+
+impl FunctionAnalysis {
+    /// The most state saved by any split of the function
+    pub fn max_saved_state(&self) -> usize {
+        self.saved_state.iter().copied().max().unwrap_or(0)
+    }
+
+    fn write_csv_row(&self, file: &str, ignored: &str, split_error: &str, output: &mut dyn Write) -> Result<()> {
+        writeln!(output, "{file},{},{},{},{},{},{},{},{},{ignored},{}",
+                 self.name,
+                 self.instruction_count,
+                 self.load_count,
+                 self.store_count,
+                 self.split_points,
+                 self.skip_safe_split_points,
+                 self.max_chain_length,
+                 self.max_saved_state(),
+                 csv_field(split_error))?;
+        Ok(())
+    }
+}
+
+/// What every row of a CSV analysis stands for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CsvRows {
+    #[default]
+    File,
+    Function,
+}
+
+const CSV_FILE_HEADER: &str = "\
+file,size,total_instructions,normal_instructions,memory_instructions,load_instructions,store_instructions,\
+split_points,skip_safe_split_points,max_chain_length,max_saved_state,split_error";
+const CSV_FUNCTION_HEADER: &str = "\
+file,function,instructions,load_instructions,store_instructions,\
+split_points,skip_safe_split_points,max_chain_length,max_saved_state,ignored,split_error";
+
+/// Write the analyses of several modules.
+/// CSV is written as a single table under one header, optionally ending in a row of totals.
+/// JSON is written as an array of reports.
+pub fn write_reports(
+    reports: &[AnalysisReport],
+    format: OutputFormat,
+    rows: CsvRows,
+    total: bool,
+    output: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        OutputFormat::Normal => {
+            for (i, report) in reports.iter().enumerate() {
+                if i > 0 {
+                    writeln!(output)?;
+                }
+                report.write_normal(output)?;
+            }
+        }
+        OutputFormat::CSV => match rows {
+            CsvRows::File => {
+                writeln!(output, "{CSV_FILE_HEADER}")?;
+                for report in reports {
+                    report.write_csv_row(output)?;
+                }
+                if total {
+                    // Sums of the counts, and the largest chain and saved state of any transaction
+                    let total = AnalysisReport {
+                        file: Some(TOTAL_ROW.into()),
+                        size: reports.iter().map(|report| report.size).sum(),
+                        instruction_count: reports.iter().map(|report| report.instruction_count).sum(),
+                        load_count: reports.iter().map(|report| report.load_count).sum(),
+                        store_count: reports.iter().map(|report| report.store_count).sum(),
+                        functions: reports.iter().flat_map(|report| report.transactions().cloned()).collect(),
+                        split_error: failed_splits(reports),
+                    };
+                    total.write_csv_row(output)?;
+                }
+            }
+            CsvRows::Function => {
+                writeln!(output, "{CSV_FUNCTION_HEADER}")?;
+                for report in reports {
+                    for function in &report.functions {
+                        let split_error = report.split_error.as_deref().unwrap_or_default();
+                        function.write_csv_row(report.file_name(), yes_no(function.ignored), split_error, output)?;
+                    }
+                }
+                if total {
+                    // Totals of the transaction functions, ignored functions are left out as in the file totals
+                    let transactions = || reports.iter().flat_map(|report| report.transactions());
+                    let total = FunctionAnalysis {
+                        name: String::default(),
+                        instruction_count: transactions().map(|function| function.instruction_count).sum(),
+                        load_count: transactions().map(|function| function.load_count).sum(),
+                        store_count: transactions().map(|function| function.store_count).sum(),
+                        ignored: false,
+                        split_points: transactions().map(|function| function.split_points).sum(),
+                        skip_safe_split_points: transactions().map(|function| function.skip_safe_split_points).sum(),
+                        max_chain_length: transactions().map(|function| function.max_chain_length).max().unwrap_or(0),
+                        saved_state: transactions().map(|function| function.max_saved_state()).max().into_iter().collect(),
+                    };
+                    total.write_csv_row(TOTAL_ROW, "", &failed_splits(reports).unwrap_or_default(), output)?;
+                }
+            }
+        },
+        OutputFormat::JSON => {
+            serde_json::to_writer_pretty(&mut *output, reports)?;
+            writeln!(output)?;
+        }
+    }
+    Ok(())
+}
+
+const TOTAL_ROW: &str = "total";
+
+/// Noted in the row of totals, as the split figures of modules that could not be split are left at 0
+fn failed_splits(reports: &[AnalysisReport]) -> Option<String> {
+    let failed = reports.iter().filter(|report| report.split_error.is_some()).count();
+    (failed > 0).then(|| format!("{failed} of {} modules could not be split", reports.len()))
+}
+
+/// Quote a CSV field if it holds a separator or quote
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+/// Count the instructions of every function of a module and split it,
+/// once at every memory access and once with `--skip-safe`, to find the suspend points of its transaction functions
+pub fn analyze_wat(input: &str) -> Result<AnalysisReport> {
+    let buffer = ParseBuffer::new(input)?;
+    let wat = parse::<Wat>(&buffer)?;
+    let fields = extract_module_fields(&wat)?;
+    let context = ModuleContext::from_module_fields(fields)?;
+    let lines = input.split('\n').collect::<Vec<&str>>();
+    let mut analysis = AnalysisReport {
+        size: input.len(),
+        ..AnalysisReport::default()
+    };
+    let mut function_index = 0;
+    for field in fields {
+        match field {
+            ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Func(_)) => function_index += 1,
+            ModuleField::Func(func) => {
+                let name = context.function_name(function_index);
+                function_index += 1;
+                let (signature_line, _) = func.span.linecol_in(input);
+                let mut function = FunctionAnalysis {
+                    ignored: name.starts_with(IGNORE_FUNC_PREFIX)
+                        || lines.get(signature_line).is_some_and(|signature| is_nosplit(signature)),
+                    name,
+                    ..FunctionAnalysis::default()
+                };
+                let FuncKind::Inline { expression, .. } = &func.kind else {
+                    return Err(anyhow!("FuncKind is not inline"));
+                };
+                for instruction in expression.instrs.iter() {
+                    function.instruction_count += 1;
+                    if let InstructionType::Memory(ty) = InstructionType::try_from(instruction)? {
+                        match ty {
+                            MemoryInstructionType::Load { .. } => function.load_count += 1,
+                            MemoryInstructionType::Store { .. } => function.store_count += 1
+                        }
+                    }
+                }
+                analysis.functions.push(function);
+            }
+            _ => {}
+        }
+    }
+    for function in analysis.functions.iter().filter(|function| !function.ignored) {
+        analysis.instruction_count += function.instruction_count;
+        analysis.load_count += function.load_count;
+        analysis.store_count += function.store_count;
+    }
+
+    let splits = transform_wat(&wat, &lines, &SplitOptions::default()).and_then(|always| {
+        transform_wat(&wat, &lines, &SplitOptions::default().policy(SkipSafe)).map(|skip_safe| (always, skip_safe))
+    });
+    match splits {
+        Ok((always, skip_safe)) => {
+            for function in analysis.functions.iter_mut().filter(|function| !function.ignored) {
+                analyze_splits(function, &always, &skip_safe);
+            }
+        }
+        Err(err) => analysis.split_error = Some(err.to_string()),
+    }
+    Ok(analysis)
+}
+
+fn analyze_splits(function: &mut FunctionAnalysis, always: &TransformOutput, skip_safe: &TransformOutput) {
+    function.split_points = always.split_counts.get(&function.name).copied().unwrap_or(0);
+    function.skip_safe_split_points = skip_safe.split_counts.get(&function.name).copied().unwrap_or(0);
+    function.max_chain_length = always.max_chain_length(&function.name);
+    function.saved_state = always
+        .microtransactions
+        .iter()
+        .filter(|microtransaction| {
+            microtransaction.function == function.name && microtransaction.name != function.name
+        })
+        .map(|microtransaction| microtransaction.saved_state)
+        .collect();
+}
@@ -0,0 +1,79 @@
+use std::ops::Range;
+
+use wast::token::Span;
+
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::utils::get_offset_from_line_index;
+
+const ANNOTATION_PREFIX: &str = "@chop:";
+const NOSPLIT: &str = "nosplit";
+const PRIVATE_REGION: &str = "private-region";
+
+/// Split control given as comments in the input.
+///
+/// `;; @chop:nosplit` at the end of a function signature leaves the function as it is,
+/// and at the end of an instruction lets the instruction be performed without a split.
+/// `;; @chop:private-region 0x1000..0x2000` anywhere in the module marks the addresses in the range
+/// as private to the transaction, so that accesses known to lie inside of it are not split.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Annotations {
+    pub private_regions: Vec<Range<u64>>,
+}
+
+impl Annotations {
+    pub fn from_lines(lines: &[&str]) -> Result<Self> {
+        let mut annotations = Self::default();
+        for (i, line) in lines.iter().enumerate() {
+            let Some((column, annotation)) = find_annotation(line) else {
+                continue;
+            };
+            let span = Span::from_offset(get_offset_from_line_index(lines, i) + column);
+            let (name, argument) = annotation.split_once(char::is_whitespace).unwrap_or((annotation, ""));
+            match name {
+                NOSPLIT if argument.trim().is_empty() => {}
+                PRIVATE_REGION => {
+                    let region = parse_address_range(argument.trim())
+                        .ok_or_else(|| ChopError::invalid_annotation(annotation).at(span))?;
+                    annotations.private_regions.push(region);
+                }
+                _ => return Err(ChopError::invalid_annotation(annotation).at(span)),
+            }
+        }
+        Ok(annotations)
+    }
+
+    /// Whether every byte in `addresses` lies inside of a single private region
+    pub fn is_private(&self, addresses: &Range<u64>) -> bool {
+        self.private_regions
+            .iter()
+            .any(|region| region.start <= addresses.start && addresses.end <= region.end)
+    }
+}
+
+/// Whether a line of the input is annotated with `@chop:nosplit`
+pub fn is_nosplit(line: &str) -> bool {
+    find_annotation(line).is_some_and(|(_, annotation)| annotation == NOSPLIT)
+}
+
+/// The annotation in the comment ending a line, along with the column it starts at.
+/// Only a comment starting with the annotation prefix is an annotation, any other comment may mention it.
+fn find_annotation(line: &str) -> Option<(usize, &str)> {
+    let comment_start = line.find(";;")? + ";;".len();
+    let comment = line[comment_start..].trim_start();
+    let annotation = comment.strip_prefix(ANNOTATION_PREFIX)?.trim_end();
+    Some((line.len() - comment.len(), annotation))
+}
+
+/// A range of addresses as `start..end`, in decimal or hexadecimal
+pub fn parse_address_range(region: &str) -> Option<Range<u64>> {
+    let (start, end) = region.split_once("..")?;
+    let [start, end] = [start, end].map(|bound| {
+        let bound = bound.trim();
+        match bound.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => bound.parse().ok(),
+        }
+    });
+    let (start, end) = (start?, end?);
+    (start < end).then_some(start..end)
+}
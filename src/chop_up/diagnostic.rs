@@ -0,0 +1,71 @@
+use std::fmt::{Display, Formatter};
+
+use crate::chop_up::error::ChopError;
+
+/// A [`ChopError`] rendered against its input, pointing out the offending line:
+///
+/// ```text
+/// Unbalanced stack - input program is malformed
+///  --> transaction.wat:3:9
+///   |
+/// 3 |         i32.add
+///   |         ^^^^^^^
+///   = in function $underflow
+/// ```
+pub struct Diagnostic<'a> {
+    error: &'a ChopError,
+    path: &'a str,
+    source: &'a str,
+}
+
+impl<'a> Diagnostic<'a> {
+    pub fn new(error: &'a ChopError, path: &'a str, source: &'a str) -> Self {
+        Self { error, path, source }
+    }
+}
+
+impl Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.error.message())?;
+        let location = self
+            .error
+            .span()
+            .filter(|span| span.offset() < self.source.len())
+            .map(|span| span.linecol_in(self.source));
+        let Some((line_index, column)) = location else {
+            write!(f, " --> {}", self.path)?;
+            return write_function_note(f, self.error, 1);
+        };
+
+        let line = self.source.split('\n').nth(line_index).unwrap_or_default();
+        let line_number = (line_index + 1).to_string();
+        let gutter = " ".repeat(line_number.len());
+        let prefix = &line[..column];
+        write!(
+            f,
+            "{gutter}--> {}:{line_number}:{}\n{gutter} |\n{line_number} | {line}\n{gutter} | ",
+            self.path,
+            prefix.chars().count() + 1,
+        )?;
+        // Tabs are kept so the caret lines up with the source line however tabs are displayed
+        let padding = prefix
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let token_len = line[column..]
+            .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+            .next()
+            .map(|token| token.chars().count())
+            .unwrap_or_default()
+            .max(1);
+        write!(f, "{padding}{}", "^".repeat(token_len))?;
+        write_function_note(f, self.error, gutter.len())
+    }
+}
+
+fn write_function_note(f: &mut Formatter<'_>, error: &ChopError, gutter_len: usize) -> std::fmt::Result {
+    match error.function() {
+        Some(function) => write!(f, "\n{} = in function ${function}", " ".repeat(gutter_len)),
+        None => Ok(()),
+    }
+}
@@ -1,33 +1,68 @@
-use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
 
 use itertools::Itertools;
 
+use crate::chop_up::annotation::Annotations;
+use crate::chop_up::error::Result;
 use crate::chop_up::function::Function;
 use crate::chop_up::instruction::{DataType, InstructionType, MemoryInstructionType};
-use crate::chop_up::instruction_stream::{Instruction, StackEffect, StackValue};
+use crate::chop_up::instruction_stream::{Instruction, LocalIndexSpace, StackEffect, StackValue};
+use crate::chop_up::module::ModuleContext;
+use crate::chop_up::options::{AbiLayout, ExplainLevel, SplitOptions};
+use crate::chop_up::policy::SplitPolicy;
+use crate::chop_up::source_map::SourceMap;
 use crate::chop_up::utils::*;
 
 pub struct WatEmitter<'a> {
-    output_writer: &'a mut dyn Write,
-    pub skip_safe_splits: bool,
+    output: &'a mut String,
+    pub policy: Rc<dyn SplitPolicy>,
+    /// Positions of the accesses declared by the split the function being emitted continues from,
+    /// performed without a split of their own
+    pub declared: HashSet<usize>,
+    pub annotations: Annotations,
+    pub unsplit_accesses: usize,
+    /// Positions of the accesses yielded in a utx, by the original function
+    pub declared_accesses: BTreeMap<String, BTreeSet<usize>>,
+    pub split_globals: bool,
+    pub context: ModuleContext,
     pub state_base: usize,
     stack_base: usize,
     pub utx_function_names: Vec<(usize, String)>,
     pub current_scope_level: usize,
-    explain: bool,
+    pub abi: AbiLayout,
+    explain: ExplainLevel,
     state_usage: Vec<usize>,
+    pub source_map: SourceMap,
+    /// State used by the values saved for each split function, the largest of all places splitting to it
+    pub saved_state: HashMap<String, usize>,
+    /// Split functions each function may continue with, in the order the splits are emitted
+    pub successors: HashMap<String, Vec<String>>,
+    /// Global holding the shadow stack pointer, as referred to in the output, saved in state across splits
+    shadow_stack_pointer: Option<(String, DataType)>,
+    /// Instructions saving and restoring values across splits, by the microtransaction they are emitted in
+    pub state_instructions: BTreeMap<String, usize>,
+    /// Name of the microtransaction being emitted
+    current_function: String,
 }
 
 impl<'a> WatEmitter<'a> {
     pub fn new(
-        output_writer: &'a mut dyn Write,
-        state_base: usize,
-        skip_safe_splits: bool,
-        explain: bool,
+        output: &'a mut String,
+        options: &SplitOptions,
+        context: ModuleContext,
     ) -> Self {
+        let state_base = options.state_size;
         Self {
-            output_writer,
-            skip_safe_splits,
+            output,
+            policy: options.policy.clone(),
+            declared: HashSet::default(),
+            annotations: Annotations::default(),
+            unsplit_accesses: 0,
+            declared_accesses: BTreeMap::default(),
+            split_globals: options.split_globals,
+            context,
             state_base,
             // The first part of state is used by user state
             // The next 8 bytes for saving store values over splits
@@ -35,16 +70,52 @@ impl<'a> WatEmitter<'a> {
             stack_base: state_base + 8,
             utx_function_names: Vec::default(),
             current_scope_level: 0,
-            explain,
+            abi: options.abi.clone(),
+            explain: options.explain,
             state_usage: Vec::default(),
+            source_map: SourceMap::default(),
+            saved_state: HashMap::default(),
+            successors: HashMap::default(),
+            shadow_stack_pointer: None,
+            state_instructions: BTreeMap::default(),
+            current_function: String::default(),
         }
     }
 
+    /// Save the shadow stack pointer across splits, right after the store value.
+    /// Transactions interleaved with this one move the pointer of their own stacks in between microtransactions.
+    pub fn set_shadow_stack_pointer(&mut self, global: String, ty: DataType) {
+        self.stack_base = self.state_base + 8 + ty.size();
+        self.shadow_stack_pointer = Some((global, ty));
+    }
+
+    pub fn emit_save_shadow_stack_pointer(&mut self) {
+        let Some((global, ty)) = self.shadow_stack_pointer.clone() else {
+            return;
+        };
+        self.emit_state_instruction("local.get $state", Some("Save shadow stack pointer".into()));
+        self.emit_state_instruction(&format!("global.get {global}"), None);
+        self.emit_state_instruction(&format!("{}.store offset={}", ty.as_str(), self.state_base + 8), None);
+    }
+
+    pub fn emit_restore_shadow_stack_pointer(&mut self) {
+        let Some((global, ty)) = self.shadow_stack_pointer.clone() else {
+            return;
+        };
+        self.emit_state_instruction("local.get $state", Some("Restore shadow stack pointer".into()));
+        self.emit_state_instruction(&format!("{}.load offset={}", ty.as_str(), self.state_base + 8), None);
+        self.emit_state_instruction(&format!("global.set {global}"), None);
+    }
+
+    /// The most state past the user defined state that any split uses to carry values
+    pub fn max_state_usage(&self) -> usize {
+        self.state_usage.iter().copied().max().unwrap_or_default()
+    }
+
     pub fn writeln(&mut self, text: &str, indent: usize) {
-        let formatted_text = format!("{}{text}\n", INDENTATION_STR.repeat(indent));
-        self.output_writer
-            .write_all(formatted_text.as_ref())
-            .expect("Could not write");
+        self.output.push_str(&INDENTATION_STR.repeat(indent));
+        self.output.push_str(text);
+        self.output.push('\n');
     }
 
     fn emit_existing_locals(&mut self, local_types: &[DataType]) {
@@ -54,20 +125,21 @@ impl<'a> WatEmitter<'a> {
         }
     }
 
-    pub fn emit_locals(&mut self, instructions: &[Instruction], locals: &[DataType]) {
+    pub fn emit_locals(&mut self, instructions: &[Instruction], locals: &[DataType]) -> Result<()> {
         self.emit_existing_locals(locals);
 
         // TODO - optimally emit locals
         if true {
             //self.skip_safe_splits {
             self.emit_all_locals();
-            return;
+            return Ok(());
         }
         // Figure out which stack juggler locals are needed to be able to save the stack,
         // and load/store arguments
         let mut stack = Vec::new();
+        let local_index_space = LocalIndexSpace::with_utx_params(locals);
         for instruction in instructions {
-            if let InstructionType::Memory(instr_type) = InstructionType::from(instruction) {
+            if let InstructionType::Memory(instr_type) = InstructionType::try_from(instruction)? {
                 self.emit_instruction(&format!("(local ${ADDRESS_LOCAL_NAME} i32)"), None);
                 stack.pop();
                 let mut types = Vec::new();
@@ -88,7 +160,7 @@ impl<'a> WatEmitter<'a> {
                 });
                 break;
             }
-            match StackEffect::from_instruction(instruction, locals) {
+            match StackEffect::from_instruction(instruction, &local_index_space, &self.context)? {
                 StackEffect::Normal { remove_n, add, .. } => {
                     for _ in 0..remove_n {
                         stack.pop();
@@ -100,6 +172,7 @@ impl<'a> WatEmitter<'a> {
                 StackEffect::Return => stack.clear(),
             }
         }
+        Ok(())
     }
 
     pub fn emit_all_locals(&mut self) {
@@ -113,7 +186,21 @@ impl<'a> WatEmitter<'a> {
         }
     }
 
+    /// Annotation pointing an instruction carried over from the input back to its line
+    pub fn source_annotation(&self, instruction: &Instruction) -> Option<String> {
+        (self.explain == ExplainLevel::Source).then(|| format!("line {}", instruction.index + 1))
+    }
+
+    pub fn emit_param_shadows(&mut self, param_shadows: &[(u32, u32)]) {
+        for (i, (param, shadow)) in param_shadows.iter().enumerate() {
+            let annotation = (i == 0).then(|| "Copy reassigned parameters to locals".to_string());
+            self.emit_instruction(&format!("local.get {param}"), annotation);
+            self.emit_instruction(&format!("local.set {shadow}"), None);
+        }
+    }
+
     pub fn emit_utx_func_signature(&mut self, func_name: &str) {
+        self.current_function = func_name.to_string();
         self.writeln(
             &format!("(func ${} {TRANSACTION_FUNCTION_SIGNATURE}", func_name),
             MODULE_MEMBER_INDENT,
@@ -121,7 +208,7 @@ impl<'a> WatEmitter<'a> {
     }
 
     pub fn emit_instruction(&mut self, instruction: &str, annotation: Option<String>) {
-        let instruction = if self.explain {
+        let instruction = if self.explain >= ExplainLevel::Annotate {
             match annotation {
                 Some(annotation) => format!("{instruction:<30};;{annotation}"),
                 None => instruction.into(),
@@ -132,18 +219,59 @@ impl<'a> WatEmitter<'a> {
         self.writeln(&instruction, INSTRUCTION_INDENT + self.current_scope_level);
     }
 
+    /// Emit an instruction saving or restoring a value across a split, counted as overhead of the split
+    pub fn emit_state_instruction(&mut self, instruction: &str, annotation: Option<String>) {
+        *self.state_instructions.entry(self.current_function.clone()).or_default() += 1;
+        self.emit_instruction(instruction, annotation);
+    }
+
+    /// Offset in state at which the stack value at `index` is saved.
+    /// The stack is saved in segments, each laid out after the values below it with its top value first,
+    /// such that the part of the stack belonging to every scope can be saved and restored independently.
+    fn stack_value_offset(&self, stack: &[StackValue], segment: Range<usize>, index: usize) -> usize {
+        self.stack_base + stack_size(&stack[..segment.start]) + stack_size(&stack[index + 1..segment.end])
+    }
+
+    /// Save the stack from `from` and up, consuming it, along with all locals
+    /// Returns the bytes of state, past the user defined state, the saved values reach up to
     pub fn emit_save_stack_and_locals(
         &mut self,
         stack: &[StackValue],
         from: usize,
-        keep_stack: bool,
+        locals: &[DataType],
+    ) -> usize {
+        self.emit_save_stack(stack, from..stack.len(), false);
+        self.emit_save_locals(locals, self.stack_base + stack_size(stack))
+    }
+
+    /// Save the part of the stack belonging to an enclosing scope along with all locals,
+    /// leaving the stack as it was.
+    /// Values above `until` are part of the new scope, but must be moved out of the way.
+    pub fn emit_save_enclosing_stack_and_locals(
+        &mut self,
+        stack: &[StackValue],
+        from: usize,
+        until: usize,
         locals: &[DataType],
     ) {
-        let already_saved_size: usize = stack[..from].iter().map(|value| value.ty.size()).sum();
-        let mut offset = self.stack_base + already_saved_size;
-        let stack = &stack[from..];
+        if until == stack.len() && until - from == 1 {
+            // A single value on top of the stack can be saved in place
+            self.emit_save_stack(stack, from..until, true);
+        } else if until > from {
+            // Values of the new scope are set aside where the locals are saved after them
+            self.emit_save_stack(stack, until..stack.len(), false);
+            self.state_usage.push(self.stack_base + stack_size(stack) - self.state_base);
+            self.emit_save_stack(stack, from..until, false);
+            self.emit_restore_stack(stack, from, until);
+            self.emit_restore_stack(stack, until, stack.len());
+        }
+        self.emit_save_locals(locals, self.stack_base + stack_size(&stack[..until]));
+    }
+
+    /// Save the segment of the stack, which must be on top of the stack
+    fn emit_save_stack(&mut self, stack: &[StackValue], segment: Range<usize>, keep_stack: bool) {
         let mut stack_save_instructions = Vec::default();
-        for StackValue { ty, .. } in stack.iter().rev() {
+        for (i, StackValue { ty, .. }) in stack.iter().enumerate().take(segment.end).skip(segment.start).rev() {
             let ty_str = ty.as_str();
             let instructions = [
                 format!(
@@ -152,12 +280,15 @@ impl<'a> WatEmitter<'a> {
                 ),
                 "local.get $state".to_string(),
                 format!("local.get ${ty_str}_{STACK_JUGGLER_NAME}"),
-                format!("{ty_str}.store offset={offset}"),
+                format!(
+                    "{ty_str}.store offset={offset}",
+                    offset = self.stack_value_offset(stack, segment.clone(), i)
+                ),
             ];
-            offset += ty.size();
             stack_save_instructions.extend_from_slice(&instructions);
         }
 
+        let stack = &stack[segment];
         for (i, instruction) in stack_save_instructions.iter().enumerate() {
             let annotation = match i {
                 0 => Some(format!(
@@ -170,10 +301,11 @@ impl<'a> WatEmitter<'a> {
                 )),
                 _ => None,
             };
-            self.emit_instruction(instruction, annotation);
+            self.emit_state_instruction(instruction, annotation);
         }
+    }
 
-
+    fn emit_save_locals(&mut self, locals: &[DataType], mut offset: usize) -> usize {
         let mut local_save_instructions = Vec::default();
         for (i, ty) in locals.iter().enumerate() {
             let ty_str = ty.as_str();
@@ -189,7 +321,8 @@ impl<'a> WatEmitter<'a> {
             local_save_instructions.extend_from_slice(&instructions);
         }
 
-        self.state_usage.push(offset - self.state_base);
+        let state_usage = offset - self.state_base;
+        self.state_usage.push(state_usage);
 
         for (i, instruction) in local_save_instructions.iter().enumerate() {
             let annotation = match i {
@@ -199,26 +332,25 @@ impl<'a> WatEmitter<'a> {
                 )),
                 _ => None,
             };
-            self.emit_instruction(instruction, annotation);
+            self.emit_state_instruction(instruction, annotation);
         }
+        state_usage
     }
 
     pub fn emit_restore_stack(&mut self, stack: &[StackValue], from: usize, until: usize) {
-        let stack_size: usize = stack[..until]
-            .iter()
-            .map(|StackValue { ty, .. }| ty.size())
-            .sum();
-        let mut offset = self.stack_base + stack_size;
-        let stack = &stack[from..until];
-        let instructions = stack.iter().flat_map(|StackValue { ty, .. }| {
-            offset -= ty.size();
+        let instructions = (from..until).flat_map(|i| {
             [
                 "local.get $state".to_string(),
-                format!("{}.load offset={offset}", ty.as_str()),
+                format!(
+                    "{}.load offset={offset}",
+                    stack[i].ty.as_str(),
+                    offset = self.stack_value_offset(stack, from..until, i)
+                ),
             ]
-        });
+        }).collect::<Vec<String>>();
 
-        for (i, instruction) in instructions.enumerate() {
+        let stack = &stack[from..until];
+        for (i, instruction) in instructions.iter().enumerate() {
             let annotation = match i {
                 0 => Some(format!(
                     "Restore stack - [{stack}]",
@@ -230,7 +362,7 @@ impl<'a> WatEmitter<'a> {
                 )),
                 _ => None,
             };
-            self.emit_instruction(&instruction, annotation);
+            self.emit_state_instruction(instruction, annotation);
         }
     }
 
@@ -239,11 +371,7 @@ impl<'a> WatEmitter<'a> {
         locals: &[DataType],
         stack: &[StackValue],
     ) {
-        let mut offset = self.stack_base
-            + stack
-            .iter()
-            .map(|StackValue { ty, .. }| ty.size())
-            .sum::<usize>();
+        let mut offset = self.stack_base + stack_size(stack);
         let instructions = locals.iter().enumerate().flat_map(|(i, ty)| {
             let ty_str = ty.as_str();
             let instructions = [
@@ -266,17 +394,43 @@ impl<'a> WatEmitter<'a> {
             } else {
                 None
             };
-            self.emit_instruction(&instruction, annotation);
+            self.emit_state_instruction(&instruction, annotation);
         }
     }
 
+    /// Index of the first microtransaction in the table.
+    /// Entries of an existing table keep their indices, index 0 is always reserved for NULL.
+    pub fn table_base(&self) -> usize {
+        self.context
+            .table
+            .as_ref()
+            .map(|table| table.size as usize)
+            .unwrap_or(0)
+            .max(1)
+    }
+
+    /// Emit the table microtransaction entries are added to, the first table of the module grown to fit them
     pub fn emit_funcref_table(&mut self) {
-        if !self.utx_function_names.is_empty() {
-            self.writeln(
-                &format!("(table {} funcref)", self.utx_function_names.len() + 1, ),
-                MODULE_MEMBER_INDENT,
-            );
+        let table_base = self.table_base();
+        let table_id = self
+            .context
+            .table
+            .as_ref()
+            .and_then(|table| table.name.as_ref())
+            .map(|name| format!("${name} "))
+            .unwrap_or_default();
+        self.writeln(
+            &format!(
+                "(table {table_id}{} funcref)",
+                table_base + self.utx_function_names.len()
+            ),
+            MODULE_MEMBER_INDENT,
+        );
+    }
 
+    fn emit_funcref_elem(&mut self) {
+        if !self.utx_function_names.is_empty() {
+            let table_base = self.table_base();
             let function_names = self
                 .utx_function_names
                 .iter()
@@ -285,7 +439,7 @@ impl<'a> WatEmitter<'a> {
                 .join(" ");
 
             self.writeln(
-                &format!("(elem (i32.const 1) func {})", &function_names),
+                &format!("(elem (i32.const {table_base}) func {})", &function_names),
                 MODULE_MEMBER_INDENT,
             );
         }
@@ -296,12 +450,19 @@ impl<'a> WatEmitter<'a> {
     }
 
     pub fn emit_end_module(&mut self) {
-        self.emit_funcref_table();
+        // An existing table is emitted in its own place, keeping the indices of the tables after it
+        if self.context.table.is_none() && !self.utx_function_names.is_empty() {
+            self.emit_funcref_table();
+        }
+        self.emit_funcref_elem();
         self.writeln("(memory 10)", MODULE_MEMBER_INDENT);
-        self.writeln(
-            "(type $utx_f (func (param i32 i32 i32) (result i32)))",
-            MODULE_MEMBER_INDENT,
-        );
+        // Modules that already declare the microtransaction type keep their declaration
+        if !self.context.types.iter().any(|ty| ty.name.as_deref() == Some("utx_f")) {
+            self.writeln(
+                "(type $utx_f (func (param i32 i32 i32) (result i32)))",
+                MODULE_MEMBER_INDENT,
+            );
+        }
         self.writeln(")", MODULE_INDENT);
     }
 
@@ -321,6 +482,11 @@ impl<'a> WatEmitter<'a> {
     }
 }
 
+/// Bytes taken up by the values when saved in state
+fn stack_size(stack: &[StackValue]) -> usize {
+    stack.iter().map(|StackValue { ty, .. }| ty.size()).sum()
+}
+
 const TRANSACTION_FUNCTION_SIGNATURE: &str =
     "(type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)";
 const INSTRUCTION_INDENT: usize = 2;
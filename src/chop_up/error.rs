@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use wast::token::Span;
+
+use crate::chop_up::diagnostic::Diagnostic;
+use crate::chop_up::validate::ValidationError;
+
+pub type Result<T> = std::result::Result<T, ChopError>;
+
+/// Reasons a module could not be split.
+///
+/// Errors found inside of a function carry its name and, where known,
+/// the span in the input text that caused them.
+#[derive(Debug)]
+pub enum ChopError {
+    /// The input is not well-formed WebAssembly text
+    Parse(wast::Error),
+    /// An instruction whose effect on memory or the stack is not modelled
+    UnsupportedInstruction {
+        instruction: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// A value type other than the four numeric types
+    UnsupportedType {
+        ty: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// A module construct that can not be transformed, such as an imported table
+    UnsupportedFeature {
+        feature: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// The stack or scopes of a function do not balance
+    MalformedStack {
+        reason: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// A reference to a local, global or type that is not declared
+    UndefinedReference {
+        reference: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// A value saved across a split, or an address passed in the utx, does not fit the microtransaction ABI
+    AbiOverflow {
+        reason: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// A `;; @chop:` comment that is not a known annotation
+    InvalidAnnotation {
+        annotation: String,
+        function: Option<String>,
+        span: Option<Span>,
+    },
+    /// The transformed module is not valid WebAssembly
+    Invalid(ValidationError),
+    /// The transformed module could not be written
+    Io(std::io::Error),
+}
+
+impl ChopError {
+    pub fn unsupported_instruction(instruction: impl Display) -> Self {
+        Self::UnsupportedInstruction {
+            instruction: instruction.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn unsupported_type(ty: impl Display) -> Self {
+        Self::UnsupportedType {
+            ty: ty.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn unsupported_feature(feature: impl Display) -> Self {
+        Self::UnsupportedFeature {
+            feature: feature.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn malformed_stack(reason: impl Display) -> Self {
+        Self::MalformedStack {
+            reason: reason.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn undefined_reference(reference: impl Display) -> Self {
+        Self::UndefinedReference {
+            reference: reference.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn abi_overflow(reason: impl Display) -> Self {
+        Self::AbiOverflow {
+            reason: reason.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    pub fn invalid_annotation(annotation: impl Display) -> Self {
+        Self::InvalidAnnotation {
+            annotation: annotation.to_string(),
+            function: None,
+            span: None,
+        }
+    }
+
+    /// Name of the function the error was found in
+    pub fn function(&self) -> Option<&str> {
+        match self {
+            Self::UnsupportedInstruction { function, .. }
+            | Self::UnsupportedType { function, .. }
+            | Self::UnsupportedFeature { function, .. }
+            | Self::MalformedStack { function, .. }
+            | Self::UndefinedReference { function, .. }
+            | Self::AbiOverflow { function, .. }
+            | Self::InvalidAnnotation { function, .. } => function.as_deref(),
+            Self::Invalid(err) => err.function.as_deref(),
+            Self::Parse(_) | Self::Io(_) => None,
+        }
+    }
+
+    /// Location in the input text the error was caused by
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::UnsupportedInstruction { span, .. }
+            | Self::UnsupportedType { span, .. }
+            | Self::UnsupportedFeature { span, .. }
+            | Self::MalformedStack { span, .. }
+            | Self::UndefinedReference { span, .. }
+            | Self::AbiOverflow { span, .. }
+            | Self::InvalidAnnotation { span, .. } => *span,
+            Self::Parse(err) => Some(err.span()),
+            Self::Invalid(_) | Self::Io(_) => None,
+        }
+    }
+
+    /// Attribute the error to a function, unless it already is
+    pub fn in_function(mut self, name: &str) -> Self {
+        if let Some((function, _)) = self.location_mut() {
+            function.get_or_insert_with(|| name.into());
+        }
+        self
+    }
+
+    /// Attribute the error to a span of the input, unless it already is
+    pub fn at(mut self, at: Span) -> Self {
+        if let Some((_, span)) = self.location_mut() {
+            span.get_or_insert(at);
+        }
+        self
+    }
+
+    /// Description of the error, without its location
+    pub fn message(&self) -> String {
+        match self {
+            Self::Parse(err) => err.message(),
+            Self::Invalid(err) => format!("Transformed module is invalid: {}", err.message),
+            Self::Io(err) => format!("Failed to write transformed module: {err}"),
+            Self::UnsupportedInstruction { instruction, .. } => format!("Unsupported instruction {instruction}"),
+            Self::UnsupportedType { ty, .. } => format!("Unsupported value type {ty}"),
+            Self::UnsupportedFeature { feature, .. } => format!("{feature} are not supported"),
+            Self::MalformedStack { reason, .. } => format!("{reason} - input program is malformed"),
+            Self::UndefinedReference { reference, .. } => format!("Reference to undefined {reference}"),
+            Self::AbiOverflow { reason, .. } => format!("{reason}, which does not fit the microtransaction ABI"),
+            Self::InvalidAnnotation { annotation, .. } => format!("Invalid annotation @chop:{annotation}"),
+        }
+    }
+
+    /// Render the error against the input it was found in, in the style of a compiler diagnostic
+    pub fn diagnostic<'a>(&'a self, path: &'a str, source: &'a str) -> Diagnostic<'a> {
+        Diagnostic::new(self, path, source)
+    }
+
+    fn location_mut(&mut self) -> Option<(&mut Option<String>, &mut Option<Span>)> {
+        match self {
+            Self::UnsupportedInstruction { function, span, .. }
+            | Self::UnsupportedType { function, span, .. }
+            | Self::UnsupportedFeature { function, span, .. }
+            | Self::MalformedStack { function, span, .. }
+            | Self::UndefinedReference { function, span, .. }
+            | Self::AbiOverflow { function, span, .. }
+            | Self::InvalidAnnotation { function, span, .. } => Some((function, span)),
+            Self::Parse(_) | Self::Invalid(_) | Self::Io(_) => None,
+        }
+    }
+}
+
+impl Display for ChopError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // The parse error points into the input text itself
+            Self::Parse(err) => write!(f, "{err}"),
+            // Validation errors name the function themselves
+            Self::Invalid(err) => write!(f, "{err}"),
+            Self::Io(_) => write!(f, "{}", self.message()),
+            _ => {
+                write!(f, "{}", self.message())?;
+                match self.function() {
+                    Some(function) => write!(f, " in function ${function}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Error for ChopError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        // Wrapped errors are displayed as they are, so their source is passed through
+        match self {
+            Self::Parse(err) => err.source(),
+            Self::Io(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<wast::Error> for ChopError {
+    fn from(err: wast::Error) -> Self {
+        Self::Parse(err)
+    }
+}
+
+impl From<ValidationError> for ChopError {
+    fn from(err: ValidationError) -> Self {
+        Self::Invalid(err)
+    }
+}
+
+impl From<std::io::Error> for ChopError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
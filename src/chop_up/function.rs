@@ -1,61 +1,124 @@
-use anyhow::{anyhow, Result};
-use rand::distributions::Alphanumeric;
-use rand::Rng;
 use wast::core::{Func, FuncKind};
 use wast::core::Instruction as WastInstruction;
-use wast::token::Index;
+use wast::token::{Index, Span};
 
+use crate::chop_up::annotation::is_nosplit;
+use crate::chop_up::error::{ChopError, Result};
 use crate::chop_up::instruction::{
     BenignInstructionType, BlockInstructionType, DataType, InstructionType,
 };
 use crate::chop_up::instruction_stream::{
-    index_is_param, Instruction, Scope, ScopeType, StackEffect,
+    show_index, Instruction, LocalIndexSpace, Scope, ScopeType, StackEffect, StackValue,
 };
+use crate::chop_up::module::ModuleContext;
 use crate::chop_up::utils;
-use crate::chop_up::utils::{count_parens, UTX_LOCALS};
+use crate::chop_up::utils::{count_parens, get_offset_from_line_index, UTX_LOCALS, UTX_PARAM_NAMES};
+use crate::chop_up::value_range::LocalValues;
 
 pub struct Function<'a> {
     pub name: String,
     pub signature: &'a str,
     pub local_types: Vec<DataType>,
+    /// Parameters that are assigned to, paired with the local replacing them
+    pub param_shadows: Vec<(u32, u32)>,
     pub instructions: Vec<Instruction<'a>>,
+    /// Named with the ignore prefix or annotated with `@chop:nosplit`, and emitted as it is
+    ignored: bool,
 }
 
 impl<'a> Function<'a> {
-    // TODO - this function is doing way to much work
-    pub fn new(func: &'a Func, lines: &'a [&str]) -> Result<Self> {
-        let name = match func.id.map(|id| id.name()) {
-            Some(func_name) => func_name.into(),
-            None => gen_random_func_name(),
-        };
+    /// `index` is the position of the function in the function index space
+    pub fn new(func: &'a Func, index: usize, lines: &'a [&str], context: &ModuleContext) -> Result<Self> {
+        let name = context.function_name(index);
+        Self::with_name(name.clone(), func, lines, context)
+            .map_err(|err| err.in_function(&name).at(func.span))
+    }
 
-        let (wast_instructions, mut local_types) =
+    // TODO - this function is doing way to much work
+    fn with_name(
+        name: String,
+        func: &'a Func,
+        lines: &'a [&str],
+        context: &ModuleContext,
+    ) -> Result<Self> {
+        let (wast_instructions, declared_locals) =
             if let FuncKind::Inline { expression, locals } = &func.kind {
-                let local_types = locals
+                let declared_locals = locals
                     .iter()
-                    .map(|local| local.ty.into())
-                    .collect::<Vec<DataType>>();
-                (expression.instrs.iter().as_slice(), local_types)
+                    .map(|local| Ok((local.id.map(|id| id.name().to_string()), local.ty.try_into()?)))
+                    .collect::<Result<Vec<(Option<String>, DataType)>>>()?;
+                (expression.instrs.iter().as_slice(), declared_locals)
             } else {
-                return Err(anyhow!("FuncKind is not inline"));
+                return Err(ChopError::unsupported_feature("Imported functions"));
             };
+        let mut local_types = declared_locals
+            .iter()
+            .map(|(_, ty)| *ty)
+            .collect::<Vec<DataType>>();
+        let declared_index_space = LocalIndexSpace::new(params(func, context)?, declared_locals);
 
         let function_line_index = utils::get_line_index_from_offset(lines, func.span.offset());
-        let signature = lines[function_line_index].trim();
+        let function_line = lines[function_line_index];
+        let (signature, inline_body) = split_signature(function_line);
+        let signature = signature.trim();
 
-        let function_member_base_line_index = function_line_index + 1;
-        let instruction_base_line_index = function_member_base_line_index
-            + lines[function_member_base_line_index..]
+        // A body starting on the signature line is taken to be the first instruction
+        let instruction_base_line_index = match inline_body {
+            Some(_) => function_line_index,
+            None => {
+                let function_member_base_line_index = function_line_index + 1;
+                function_member_base_line_index
+                    + lines[function_member_base_line_index..]
+                    .iter()
+                    .take_while(|line| line.contains("(local"))
+                    .count()
+            }
+        };
+        let mut instruction_lines = lines[instruction_base_line_index..]
             .iter()
-            .take_while(|line| line.contains("(local"))
-            .count();
+            .take(wast_instructions.len())
+            .copied()
+            .collect::<Vec<&str>>();
+        let mut instruction_spans = instruction_spans(lines, instruction_base_line_index, wast_instructions.len());
+        if let (Some(body), Some(line), Some(span)) =
+            (inline_body, instruction_lines.first_mut(), instruction_spans.first_mut())
+        {
+            let offset = get_offset_from_line_index(lines, function_line_index) + function_line.len() - body.len();
+            *line = body;
+            *span = Span::from_offset(offset);
+        }
+
+        let ignored = name.starts_with(IGNORE_FUNC_PREFIX) || is_nosplit(function_line);
+        // Parameters are reset on every microtransaction call,
+        // therefore any parameter that is assigned to is replaced by a local copy
+        let mut param_shadows: Vec<(u32, u32)> = Vec::new();
+        if !ignored {
+            for (instruction, span) in wast_instructions.iter().zip(instruction_spans.clone()) {
+                if let WastInstruction::LocalSet(index) | WastInstruction::LocalTee(index) = instruction {
+                    let param = declared_index_space
+                        .resolve(index)
+                        .ok_or_else(|| undeclared_local(index).at(span))?;
+                    if declared_index_space.is_param(param)
+                        && !param_shadows.iter().any(|(shadowed, _)| *shadowed == param)
+                    {
+                        let shadow = (UTX_LOCALS.len() + local_types.len()) as u32;
+                        local_types.push(
+                            declared_index_space
+                                .ty(param)
+                                .ok_or_else(|| undeclared_local(index).at(span))?,
+                        );
+                        param_shadows.push((param, shadow));
+                    }
+                }
+            }
+        }
 
         let mut instructions_with_raw_text = Vec::new();
-        let mut remapped_locals: Vec<(u32, u32)> = Vec::new();
-        for (instruction, &raw_string) in wast_instructions.iter().zip(
-            &lines[instruction_base_line_index
-                ..instruction_base_line_index + wast_instructions.len()],
-        ) {
+        for ((instruction, &raw_string), span) in wast_instructions
+            .iter()
+            .zip(&instruction_lines)
+            .zip(instruction_spans)
+        {
             let mut instruction_string = raw_string.trim().to_string();
             let mut paren_imbalance = count_parens(raw_string);
             if matches!(instruction, WastInstruction::End(_)) {
@@ -65,119 +128,110 @@ impl<'a> Function<'a> {
                 let actual_len = instruction_string.len() - paren_imbalance as usize;
                 instruction_string = instruction_string[..actual_len].trim().to_string();
             }
-            // Don't do remapping if we are simply getting the function the function
-            if !name.starts_with(IGNORE_FUNC_PREFIX) {
-                // Here there is a possibly scary assumption made.
-                // That the compiler will not use named arguments to local-instructions
-                // To fix we must also handle the [Index::Id] case
-                match instruction {
-                    WastInstruction::LocalSet(Index::Num(i, _))
-                    | WastInstruction::LocalTee(Index::Num(i, _)) => {
-                        if index_is_param(*i) {
-                            let new_name = if let Some((_, new_name)) =
-                                remapped_locals.iter().find(|(param, _)| param.eq(i))
-                            {
-                                *new_name
-                            } else {
-                                let new_name =
-                                    (UTX_LOCALS.len() + local_types.len() + remapped_locals.len())
-                                        as u32;
-                                remapped_locals.push((*i, new_name));
-                                new_name
-                            };
-                            instruction_string = format!(
-                                "{base_instruction} {new_name}",
-                                base_instruction = &instruction_string[..instruction_string.len() - 2]
-                            );
-                        }
-                    }
-                    WastInstruction::LocalGet(Index::Num(i, _)) => {
-                        if index_is_param(*i) {
-                            if let Some(new_name) = remapped_locals
-                                .iter()
-                                .find(|(param, _)| param.eq(i))
-                                .map(|(_, new_name)| *new_name)
-                            {
-                                instruction_string = format!(
-                                    "{base_instruction} {new_name}",
-                                    base_instruction =
-                                    &instruction_string[..instruction_string.len() - 2]
-                                );
-                            }
-                        }
-                    }
-                    _ => {}
+            if let Some(index) = local_index(instruction) {
+                let resolved_index = declared_index_space
+                    .resolve(index)
+                    .ok_or_else(|| undeclared_local(index).at(span))?;
+                let shadow = param_shadows
+                    .iter()
+                    .find(|(param, _)| *param == resolved_index)
+                    .map(|(_, shadow)| *shadow);
+                if let Some(shadow) = shadow {
+                    instruction_string = replace_index_operand(&instruction_string, shadow);
+                } else if matches!(index, Index::Id(_))
+                    && !name_is_emitted(&declared_index_space, resolved_index, ignored)
+                {
+                    // Local names are not carried over to the output, so named references are resolved
+                    instruction_string = replace_index_operand(&instruction_string, resolved_index);
                 }
             }
-            instructions_with_raw_text.push((instruction, instruction_string))
-        }
-
-        for _ in remapped_locals {
-            local_types.push(DataType::I32)
+            instructions_with_raw_text.push((instruction, instruction_string, span))
         }
 
         // No preprocessing is needed in this case...
-        if name.starts_with(IGNORE_FUNC_PREFIX) {
+        if ignored {
             return Ok(Self {
                 name,
                 signature,
                 local_types,
+                param_shadows,
                 instructions: instructions_with_raw_text
                     .into_iter()
-                    .map(|(instr, raw_text)| Instruction::default(instr, raw_text))
+                    .map(|(instr, raw_text, span)| Instruction::default(instr, raw_text, span))
                     .collect(),
+                ignored,
             });
         }
 
-        let mut instructions_with_text_and_stack = Vec::default();
-        let mut current_stack_state = Vec::default();
-        for (instruction, raw_string) in instructions_with_raw_text {
-            instructions_with_text_and_stack.push((
-                instruction,
-                raw_string,
-                current_stack_state.to_vec(),
-            ));
-            StackEffect::from_wast_instruction(instruction, &local_types)
-                .update_stack(&mut current_stack_state)?;
-        }
-
+        let local_index_space = declared_index_space.with_locals(&local_types);
         let mut instructions_with_stack_and_scope = Vec::default();
-        let mut current_scopes = Vec::default();
-        for (instruction, text, stack) in instructions_with_text_and_stack {
-            if let InstructionType::Benign(BenignInstructionType::Block(ty)) =
-                InstructionType::from(instruction)
-            {
-                match ty {
+        let mut current_stack_state = Vec::default();
+        let mut current_scopes: Vec<Scope> = Vec::default();
+        let mut local_values = LocalValues::default();
+        for (instruction, text, span) in instructions_with_raw_text {
+            let stack = current_stack_state.to_vec();
+            let at_instruction = |err: ChopError| err.at(span);
+            match InstructionType::try_from(instruction).map_err(at_instruction)? {
+                InstructionType::Benign(BenignInstructionType::Block(ty)) => match ty {
                     BlockInstructionType::Block(name) => {
+                        let block_type = match instruction {
+                            WastInstruction::Block(block) => context
+                                .resolve_type_use(&block.ty)
+                                .map_err(at_instruction)?,
+                            _ => unreachable!("Only blocks open a block scope"),
+                        };
+                        // Parameters are consumed from the enclosing scope and
+                        // make up the initial stack of the block
+                        let stack_start = stack
+                            .len()
+                            .checked_sub(block_type.params.len())
+                            .ok_or_else(|| ChopError::malformed_stack("Unbalanced stack").at(span))?;
+                        local_values.enter_block();
                         current_scopes.push(Scope {
                             ty: ScopeType::Block,
                             name,
-                            stack_start: stack.len(),
+                            stack_start,
+                            params: block_type.params,
+                            results: block_type.results,
                         });
                     }
                     BlockInstructionType::End => {
-                        current_scopes.pop().ok_or(
-                            anyhow!("Unbalanced scopes - tried to remove top-level scope")
-                        )?;
+                        let scope = current_scopes.pop().ok_or_else(|| {
+                            ChopError::malformed_stack("Unbalanced scopes - tried to remove top-level scope")
+                                .at(span)
+                        })?;
+                        local_values.exit_block();
+                        // Whatever remains in the block is replaced by its results
+                        current_stack_state.truncate(scope.stack_start);
+                        current_stack_state.extend(scope.results.iter().map(|&ty| StackValue::new(ty)));
                     }
+                },
+                _ => {
+                    let operands = StackEffect::from_wast_instruction(instruction, &local_index_space, context)
+                        .and_then(|effect| effect.update_stack(&mut current_stack_state))
+                        .map_err(at_instruction)?;
+                    local_values.update(instruction, &operands, &mut current_stack_state, &local_index_space);
                 }
             }
             instructions_with_stack_and_scope.push((
                 instruction,
                 text,
+                span,
                 stack,
                 current_scopes.to_vec(),
             ))
         }
 
         let mut instructions = Vec::default();
-        for (i, (instruction, raw_text, stack, scopes)) in
+        for (i, (instruction, raw_text, span, stack, scopes)) in
         instructions_with_stack_and_scope.into_iter().enumerate()
         {
             instructions.push(Instruction::new(
                 instruction,
                 raw_text,
+                span,
                 instruction_base_line_index + i,
+                i,
                 stack,
                 scopes,
             ));
@@ -186,23 +240,117 @@ impl<'a> Function<'a> {
         Ok(Self {
             name,
             local_types,
+            param_shadows,
             signature,
             instructions,
+            ignored,
         })
     }
 
     pub fn ignore(&self) -> bool {
-        self.name.starts_with(IGNORE_FUNC_PREFIX)
+        self.ignored
+    }
+}
+
+/// Names and types of the parameters of a function, from inline declarations or its type
+fn params(func: &Func, context: &ModuleContext) -> Result<Vec<(Option<String>, DataType)>> {
+    match &func.ty.inline {
+        Some(inline) if !inline.params.is_empty() => Ok(inline
+            .params
+            .iter()
+            .map(|(id, _, ty)| Ok((id.map(|id| id.name().to_string()), (*ty).try_into()?)))
+            .collect::<Result<Vec<(Option<String>, DataType)>>>()?),
+        _ => Ok(context
+            .resolve_type_use(&func.ty)?
+            .params
+            .into_iter()
+            .map(|ty| (None, ty))
+            .collect()),
+    }
+}
+
+/// Split the line a function starts on into its signature and the rest of the line,
+/// if the body starts on that line after the name and the parenthesized fields of the signature
+fn split_signature(line: &str) -> (&str, Option<&str>) {
+    const SIGNATURE_FIELDS: [&str; 6] = ["export", "import", "type", "param", "result", "local"];
+    let Some(start) = line.find("(func") else {
+        return (line, None);
+    };
+    let mut rest = line[start + "(func".len()..].trim_start();
+    if rest.starts_with('$') {
+        rest = rest.trim_start_matches(|c: char| !c.is_whitespace() && c != '(' && c != ')').trim_start();
+    }
+    while let Some(field) = rest.strip_prefix('(') {
+        if !SIGNATURE_FIELDS.iter().any(|name| field.starts_with(name)) {
+            break;
+        }
+        let mut depth = 0;
+        let Some(end) = rest.char_indices().find_map(|(i, c)| {
+            depth += match c {
+                '(' => 1,
+                ')' => -1,
+                _ => 0,
+            };
+            (depth == 0).then_some(i + 1)
+        }) else {
+            break;
+        };
+        rest = rest[end..].trim_start();
+    }
+    // Only the closing paren of the function, or a comment, follows the signature
+    if rest.is_empty() || rest.starts_with(')') || rest.starts_with(";;") {
+        return (line, None);
+    }
+    (&line[..line.len() - rest.len()], Some(rest))
+}
+
+/// Spans of the instructions of a function, which are written one per line from `base_line_index`
+fn instruction_spans(lines: &[&str], base_line_index: usize, count: usize) -> Vec<Span> {
+    let mut offset = get_offset_from_line_index(lines, base_line_index);
+    lines[base_line_index..]
+        .iter()
+        .take(count)
+        .map(|line| {
+            let indent = line.len() - line.trim_start().len();
+            let span = Span::from_offset(offset + indent);
+            offset += line.len() + 1;
+            span
+        })
+        .collect()
+}
+
+fn undeclared_local(index: &Index) -> ChopError {
+    ChopError::undefined_reference(format_args!("local {}", show_index(index)))
+}
+
+fn local_index<'a>(instruction: &'a WastInstruction) -> Option<&'a Index<'a>> {
+    match instruction {
+        WastInstruction::LocalGet(index)
+        | WastInstruction::LocalSet(index)
+        | WastInstruction::LocalTee(index) => Some(index),
+        _ => None,
+    }
+}
+
+/// Whether a named local keeps its name in the output.
+/// Only parameters are declared with names, and transformed functions get new parameter names.
+fn name_is_emitted(locals: &LocalIndexSpace, index: u32, ignored: bool) -> bool {
+    if !locals.is_param(index) {
+        return false;
     }
+    ignored || locals.param_name(index) == UTX_PARAM_NAMES.get(index as usize).copied()
 }
 
-fn gen_random_func_name() -> String {
-    let rand_id: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(5)
-        .map(char::from)
-        .collect();
-    format!("funcid_{rand_id}")
+/// Replace the index operand of a local instruction, keeping anything that follows it
+fn replace_index_operand(instruction: &str, index: u32) -> String {
+    let mut tokens = instruction.split_whitespace();
+    let mnemonic = tokens.next().unwrap_or_default();
+    tokens.next();
+    [mnemonic.to_string(), index.to_string()]
+        .into_iter()
+        .chain(tokens.map(String::from))
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
 /// Function names that start with this prefix are not to be transformed
@@ -0,0 +1,114 @@
+use wast::core::{FuncKind, ItemKind, ModuleField};
+use wast::core::Instruction as WastInstruction;
+use wast::token::Index;
+
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::instruction::DataType;
+
+/// Start of the address region handed out to globals when they are treated as state.
+/// Chosen far above any linear memory a transaction could realistically use.
+pub const GLOBAL_ADDRESS_BASE: u32 = 0xFFFF_0000;
+/// Every global is given a slot wide enough to hold the largest value type
+const GLOBAL_ADDRESS_STRIDE: u32 = 8;
+/// Name clang gives the global holding the shadow stack pointer
+pub const STACK_POINTER_NAME: &str = "__stack_pointer";
+
+#[derive(Clone, Debug)]
+pub struct Global {
+    pub name: Option<String>,
+    pub ty: DataType,
+    pub mutable: bool,
+    /// Points into memory private to the transaction, see [`SplitOptions::private_global`](crate::SplitOptions::private_global)
+    pub private: bool,
+}
+
+impl Global {
+    /// Collect globals in index space order, imported globals come first
+    pub fn from_module_fields(fields: &[ModuleField]) -> Result<Vec<Self>> {
+        let mut imported = Vec::default();
+        let mut defined = Vec::default();
+        for field in fields {
+            match field {
+                ModuleField::Import(import) => {
+                    if let ItemKind::Global(ty) = &import.item.kind {
+                        imported.push(Self {
+                            name: import.item.id.map(|id| id.name().into()),
+                            ty: ty.ty.try_into().map_err(|err: ChopError| err.at(import.span))?,
+                            mutable: ty.mutable,
+                            private: false,
+                        });
+                    }
+                }
+                ModuleField::Global(global) => defined.push(Self {
+                    name: global.id.map(|id| id.name().into()),
+                    ty: global.ty.ty.try_into().map_err(|err: ChopError| err.at(global.span))?,
+                    mutable: global.ty.mutable,
+                    private: false,
+                }),
+                _ => {}
+            }
+        }
+        imported.append(&mut defined);
+        Ok(imported)
+    }
+}
+
+/// Find the position and definition of the global an index refers to
+pub fn resolve_global<'a>(globals: &'a [Global], index: &Index) -> Option<(u32, &'a Global)> {
+    match index {
+        Index::Num(i, _) => globals.get(*i as usize).map(|global| (*i, global)),
+        Index::Id(id) => globals
+            .iter()
+            .position(|global| global.name.as_deref() == Some(id.name()))
+            .map(|i| (i as u32, &globals[i])),
+    }
+}
+
+pub fn global_index<'a>(instruction: &'a WastInstruction) -> Option<&'a Index<'a>> {
+    match instruction {
+        WastInstruction::GlobalGet(index) | WastInstruction::GlobalSet(index) => Some(index),
+        _ => None,
+    }
+}
+
+/// The address yielded to the runtime in place of an access to the global at `index`.
+/// Only the first 8192 globals fit between the base and the end of the 32 bit address space.
+pub fn synthetic_address(index: u32) -> Result<u32> {
+    index
+        .checked_mul(GLOBAL_ADDRESS_STRIDE)
+        .and_then(|offset| GLOBAL_ADDRESS_BASE.checked_add(offset))
+        .ok_or_else(|| ChopError::abi_overflow(format_args!("No synthetic address left for global {index}")))
+}
+
+/// Find the global holding the shadow stack pointer of code compiled by clang.
+/// It is either named `__stack_pointer`, or moved by function prologues allocating a stack frame:
+/// `global.get`, `i32.const`, `i32.sub`, `local.tee`, `global.set` of the same global.
+pub fn find_shadow_stack_pointer(fields: &[ModuleField], globals: &[Global]) -> Option<u32> {
+    let is_pointer = |global: &Global| global.mutable && matches!(global.ty, DataType::I32 | DataType::I64);
+    if let Some(i) = globals
+        .iter()
+        .position(|global| is_pointer(global) && global.name.as_deref() == Some(STACK_POINTER_NAME))
+    {
+        return Some(i as u32);
+    }
+    fields
+        .iter()
+        .filter_map(|field| match field {
+            ModuleField::Func(func) => match &func.kind {
+                FuncKind::Inline { expression, .. } => Some(&expression.instrs),
+                FuncKind::Import(_) => None,
+            },
+            _ => None,
+        })
+        .find_map(|instructions| {
+            instructions.windows(5).find_map(|window| match window {
+                [WastInstruction::GlobalGet(get), WastInstruction::I32Const(_), WastInstruction::I32Sub, WastInstruction::LocalTee(_), WastInstruction::GlobalSet(set)] =>
+                {
+                    let (index, global) = resolve_global(globals, get)?;
+                    let (set_index, _) = resolve_global(globals, set)?;
+                    (index == set_index && is_pointer(global)).then_some(index)
+                }
+                _ => None,
+            })
+        })
+}
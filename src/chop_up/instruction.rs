@@ -1,27 +1,30 @@
-use anyhow::{anyhow, Result};
 use wast::core::{Instruction as WastInstruction, ValType};
 use wast::core::Instruction::{I32Store16, I64Load32u, I64Store16};
 use WastInstruction::{
-    Block, DataDrop, ElemDrop, End, F32Load, F32Store, F64Load, F64Store,
-    I32Load, I32Load16u, I32Store, I32Store8, I64Load, I64Store, I64Store8, MemoryCopy,
-    MemoryDiscard, MemoryFill, MemoryGrow, MemoryInit, MemorySize, Return, TableCopy, TableFill,
-    TableGet, TableGrow, TableInit, TableSet, TableSize,
+    Block, DataDrop, ElemDrop, End, F32Load, F32Store, F64Load, F64Store, GlobalGet, GlobalSet,
+    I32Load, I32Load16s, I32Load16u, I32Load8s, I32Load8u, I32Store, I32Store8, I64Load,
+    I64Load16s, I64Load16u, I64Load32s, I64Load8s, I64Load8u, I64Store, I64Store32, I64Store8,
+    MemoryCopy, MemoryDiscard, MemoryFill, MemoryGrow, MemoryInit, MemorySize, Return, TableCopy,
+    TableFill, TableGet, TableGrow, TableInit, TableSet, TableSize,
 };
 
+use crate::chop_up::error::{ChopError, Result};
 use crate::chop_up::instruction::DataType::*;
-use crate::chop_up::instruction::InstructionType::{Benign, Memory};
+use crate::chop_up::instruction::InstructionType::{Benign, Global, Memory};
 use crate::chop_up::instruction_stream::Instruction;
-use crate::chop_up::instruction_stream::StackValue;
 
 #[derive(PartialEq, Clone)]
 pub enum InstructionType {
     Memory(MemoryInstructionType),
+    Global(GlobalInstructionType),
     Benign(BenignInstructionType),
 }
 
-impl From<&WastInstruction<'_>> for InstructionType {
-    fn from(value: &WastInstruction<'_>) -> Self {
-        if let Some((ty, offset, subtype)) = type_from_load(value) {
+impl TryFrom<&WastInstruction<'_>> for InstructionType {
+    type Error = ChopError;
+
+    fn try_from(value: &WastInstruction<'_>) -> Result<Self> {
+        Ok(if let Some((ty, offset, subtype)) = type_from_load(value) {
             Memory(MemoryInstructionType::Load {
                 ty,
                 offset,
@@ -34,10 +37,11 @@ impl From<&WastInstruction<'_>> for InstructionType {
                 subtype,
             })
         } else if is_other_memory_instruction(value) {
-            panic!(
-                "Unsupported instruction read when producing InstructionType - {:?}",
-                value
-            )
+            return Err(ChopError::unsupported_instruction(instruction_name(value)));
+        } else if let GlobalGet(_) = value {
+            Global(GlobalInstructionType::Get)
+        } else if let GlobalSet(_) = value {
+            Global(GlobalInstructionType::Set)
         } else {
             Benign(match value {
                 Block(id) => BenignInstructionType::Block(BlockInstructionType::Block(
@@ -47,13 +51,15 @@ impl From<&WastInstruction<'_>> for InstructionType {
                 Return => BenignInstructionType::Return,
                 _ => BenignInstructionType::Other,
             })
-        }
+        })
     }
 }
 
-impl From<&Instruction<'_>> for InstructionType {
-    fn from(value: &Instruction) -> Self {
-        Self::from(value.instr)
+impl TryFrom<&Instruction<'_>> for InstructionType {
+    type Error = ChopError;
+
+    fn try_from(value: &Instruction) -> Result<Self> {
+        Self::try_from(value.instr).map_err(|err| err.at(value.span))
     }
 }
 
@@ -71,6 +77,12 @@ pub enum BlockInstructionType {
 }
 
 #[derive(Clone, Copy, PartialEq)]
+pub enum GlobalInstructionType {
+    Get,
+    Set,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MemoryInstructionType {
     Load {
         ty: DataType,
@@ -84,41 +96,64 @@ pub enum MemoryInstructionType {
     },
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MemoryInstructionSubtype {
+    EightS,
+    EightU,
+    SixteenS,
     SixteenU,
+    ThirtyTwoS,
     ThirtyTwoU,
     Eight,
     Sixteen,
+    ThirtyTwo,
+}
+
+impl MemoryInstructionType {
+    pub fn offset(&self) -> u64 {
+        match self {
+            MemoryInstructionType::Load { offset, .. } | MemoryInstructionType::Store { offset, .. } => *offset,
+        }
+    }
+
+    /// Number of bytes accessed
+    pub fn size(&self) -> usize {
+        match self {
+            MemoryInstructionType::Load { ty, subtype, .. } | MemoryInstructionType::Store { ty, subtype, .. } => {
+                subtype.map(|subtype| subtype.size()).unwrap_or(ty.size())
+            }
+        }
+    }
 }
 
 impl MemoryInstructionSubtype {
+    pub fn size(&self) -> usize {
+        match self {
+            MemoryInstructionSubtype::EightS | MemoryInstructionSubtype::EightU | MemoryInstructionSubtype::Eight => 1,
+            MemoryInstructionSubtype::SixteenS
+            | MemoryInstructionSubtype::SixteenU
+            | MemoryInstructionSubtype::Sixteen => 2,
+            MemoryInstructionSubtype::ThirtyTwoS
+            | MemoryInstructionSubtype::ThirtyTwoU
+            | MemoryInstructionSubtype::ThirtyTwo => 4,
+        }
+    }
+
     pub fn as_str(&self) -> &'static str {
         match self {
+            MemoryInstructionSubtype::EightS => "8_s",
+            MemoryInstructionSubtype::EightU => "8_u",
+            MemoryInstructionSubtype::SixteenS => "16_s",
             MemoryInstructionSubtype::SixteenU => "16_u",
-            MemoryInstructionSubtype::Sixteen => "16",
-            MemoryInstructionSubtype::Eight => "8",
+            MemoryInstructionSubtype::ThirtyTwoS => "32_s",
             MemoryInstructionSubtype::ThirtyTwoU => "32_u",
+            MemoryInstructionSubtype::Eight => "8",
+            MemoryInstructionSubtype::Sixteen => "16",
+            MemoryInstructionSubtype::ThirtyTwo => "32",
         }
     }
 }
 
-impl MemoryInstructionType {
-    pub fn needs_split(&self, stack: &[StackValue], skip_safe_splits: bool) -> Result<bool> {
-        let needs_split = match self {
-            MemoryInstructionType::Load { .. } => {
-                let last_is_safe = stack
-                    .last()
-                    .ok_or(anyhow!("Load with empty stack - program is malformed"))?
-                    .is_safe;
-                !(last_is_safe && skip_safe_splits)
-            }
-            MemoryInstructionType::Store { .. } => true,
-        };
-        Ok(needs_split)
-    }
-}
-
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub enum DataType {
     I32,
@@ -145,14 +180,16 @@ impl DataType {
     }
 }
 
-impl From<ValType<'_>> for DataType {
-    fn from(value: ValType) -> Self {
+impl TryFrom<ValType<'_>> for DataType {
+    type Error = ChopError;
+
+    fn try_from(value: ValType) -> Result<Self> {
         match value {
-            ValType::I32 => I32,
-            ValType::I64 => I64,
-            ValType::F32 => F32,
-            ValType::F64 => F64,
-            _ => panic!("Unsupported type {:?}", value),
+            ValType::I32 => Ok(I32),
+            ValType::I64 => Ok(I64),
+            ValType::F32 => Ok(F32),
+            ValType::F64 => Ok(F64),
+            _ => Err(ChopError::unsupported_type(format_args!("{value:?}"))),
         }
     }
 }
@@ -162,8 +199,16 @@ fn type_from_load(
 ) -> Option<(DataType, u64, Option<MemoryInstructionSubtype>)> {
     match instruction {
         I32Load(arg) => Some((I32, arg.offset, None)),
+        I32Load8s(arg) => Some((I32, arg.offset, Some(MemoryInstructionSubtype::EightS))),
+        I32Load8u(arg) => Some((I32, arg.offset, Some(MemoryInstructionSubtype::EightU))),
+        I32Load16s(arg) => Some((I32, arg.offset, Some(MemoryInstructionSubtype::SixteenS))),
         I32Load16u(arg) => Some((I32, arg.offset, Some(MemoryInstructionSubtype::SixteenU))),
         I64Load(arg) => Some((I64, arg.offset, None)),
+        I64Load8s(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::EightS))),
+        I64Load8u(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::EightU))),
+        I64Load16s(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::SixteenS))),
+        I64Load16u(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::SixteenU))),
+        I64Load32s(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::ThirtyTwoS))),
         I64Load32u(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::ThirtyTwoU))),
         F32Load(arg) => Some((F32, arg.offset, None)),
         F64Load(arg) => Some((F64, arg.offset, None)),
@@ -181,12 +226,23 @@ fn type_from_store(
         I64Store(arg) => Some((I64, arg.offset, None)),
         I64Store8(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::Eight))),
         I64Store16(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::Sixteen))),
+        I64Store32(arg) => Some((I64, arg.offset, Some(MemoryInstructionSubtype::ThirtyTwo))),
         F32Store(arg) => Some((F32, arg.offset, None)),
         F64Store(arg) => Some((F64, arg.offset, None)),
         _ => None,
     }
 }
 
+/// Name of the instruction variant, without its immediates
+pub fn instruction_name(instruction: &WastInstruction) -> String {
+    let debug = format!("{instruction:?}");
+    debug
+        .split(|c: char| !c.is_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
 fn is_other_memory_instruction(instruction: &WastInstruction) -> bool {
     matches!(instruction, TableGet(_)
         | TableSet(_)
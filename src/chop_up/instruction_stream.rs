@@ -1,26 +1,38 @@
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 
-use anyhow::{anyhow, Result};
 use wast::core::Instruction::{
     self as WastInstruction, Block, Br, BrIf, Drop, End, F32Const, F32Gt, F64Const, F64Gt, I32Add,
     I32Const, I32Eq, I32Eqz, I32GtS, I32GtU, I32Load, I32Load16u, I32LtS, I32LtU, I32Mul, I32Ne,
     I32Shl, I32Store, I32Store8, I32Sub, I32WrapI64, I32Xor, I64Add, I64Const, I64Eq,
     I64ExtendI32U, I64GtS, I64GtU, I64Load, I64Load32u, I64LtS, I64LtU, I64Mul, I64Ne, I64Sub,
-    I64Xor, LocalGet, LocalSet, LocalTee, Return,
+    I64Xor, CallIndirect, GlobalGet, GlobalSet, LocalGet, LocalSet, LocalTee, Return,
+};
+use itertools::Itertools;
+use wast::token::{Index, Span};
+use WastInstruction::{
+    F32Load, F32Store, F64Load, F64Store, I32And, I32Load16s, I32Load8s, I32Load8u, I32Or,
+    I32Store16, I64And, I64Load16s, I64Load16u, I64Load32s, I64Load8s, I64Load8u, I64Or,
+    I64Store, I64Store16, I64Store32, I64Store8,
 };
-use wast::token::Index;
-use WastInstruction::{I32And, I32Store16, I64Store};
 
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::global::resolve_global;
 use crate::chop_up::instruction::{
-    BenignInstructionType, BlockInstructionType, DataType, InstructionType,
+    instruction_name, BenignInstructionType, BlockInstructionType, DataType, InstructionType,
 };
-use crate::chop_up::utils::UTX_LOCALS;
+use crate::chop_up::module::ModuleContext;
+use crate::chop_up::utils::{UTX_LOCALS, UTX_PARAM_NAMES};
+use crate::chop_up::value_range::Bounds;
 
 pub struct Instruction<'a> {
     pub instr: &'a WastInstruction<'a>,
     pub raw_text: String,
+    /// Location of the instruction in the input text
+    pub span: Span,
     pub index: usize,
+    /// Position of the instruction in the body of the function
+    pub position: usize,
     pub stack: Vec<StackValue>,
     pub scopes: Vec<Scope>,
 }
@@ -29,14 +41,18 @@ impl<'a> Instruction<'a> {
     pub fn new(
         instr: &'a WastInstruction<'a>,
         raw_text: String,
+        span: Span,
         index: usize,
+        position: usize,
         stack: Vec<StackValue>,
         scopes: Vec<Scope>,
     ) -> Self {
         Instruction {
             instr,
             raw_text,
+            span,
             index,
+            position,
             stack,
             scopes,
         }
@@ -45,10 +61,13 @@ impl<'a> Instruction<'a> {
     pub fn default(
         instr: &'a WastInstruction<'a>,
         raw_text: String,
+        span: Span,
     ) -> Self {
         Self::new(
             instr,
             raw_text,
+            span,
+            0,
             0,
             Vec::default(),
             Vec::default(),
@@ -56,28 +75,76 @@ impl<'a> Instruction<'a> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Scope {
     pub ty: ScopeType,
     pub name: Option<String>,
+    /// Height of the stack in the enclosing scope once the block parameters have been consumed
     pub stack_start: usize,
+    pub params: Vec<DataType>,
+    pub results: Vec<DataType>,
+}
+
+impl Scope {
+    /// Text opening the scope.
+    /// When re-entering a scope after a split the stack is restored inside of the block,
+    /// in which case the parameters must be left out.
+    pub fn opening_text(&self, with_params: bool) -> String {
+        let mut text = match self.ty {
+            ScopeType::Block => "(block".to_string(),
+        };
+        if let Some(name) = &self.name {
+            text.push_str(&format!(" ${name}"));
+        }
+        if with_params && !self.params.is_empty() {
+            text.push_str(&format!(" (param {})", self.params.iter().map(|ty| ty.as_str()).join(" ")));
+        }
+        if !self.results.is_empty() {
+            text.push_str(&format!(" (result {})", self.results.iter().map(|ty| ty.as_str()).join(" ")));
+        }
+        text
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum ScopeType {
     Block,
 }
 
+/// A value on the abstract stack of a function
 #[derive(Copy, Clone, Debug)]
 pub struct StackValue {
     pub ty: DataType,
+    /// Derived from a parameter, such as an address relative to `$tx`
     pub is_safe: bool,
+    /// Bounds on the value, if they are known
+    pub bounds: Option<Bounds>,
+    /// Derived from a private global, such as an address relative to the shadow stack pointer
+    pub private: bool,
+}
+
+impl StackValue {
+    pub fn new(ty: DataType) -> Self {
+        Self {
+            ty,
+            is_safe: false,
+            bounds: None,
+            private: false,
+        }
+    }
+
+    /// The value, if it is a constant
+    pub fn constant(&self) -> Option<i64> {
+        self.bounds.and_then(|bounds| bounds.as_constant())
+    }
 }
 
 impl Display for StackValue {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let safe_string = if self.is_safe { " - safe" } else { "" };
-        write!(f, "({:?}{safe_string})", self.ty)
+        let private_string = if self.private { " - private" } else { "" };
+        let bounds_string = self.bounds.map(|bounds| format!(" = {bounds}")).unwrap_or_default();
+        write!(f, "({:?}{safe_string}{private_string}{bounds_string})", self.ty)
     }
 }
 
@@ -94,13 +161,23 @@ impl StackEffect {
     fn new(remove_n: usize, add: Option<DataType>, is_safe: bool, preserves_safety: bool) -> Self {
         Self::Normal {
             remove_n,
-            add: add.map(|ty| StackValue { ty, is_safe }),
+            add: add.map(|ty| StackValue { is_safe, ..StackValue::new(ty) }),
             preserves_safety,
         }
     }
 
-    pub fn update_stack(&self, stack: &mut Vec<StackValue>) -> Result<()> {
+    fn constant(ty: DataType, value: i64) -> Self {
+        Self::Normal {
+            remove_n: 0,
+            add: Some(StackValue { bounds: Some(Bounds::constant(value)), ..StackValue::new(ty) }),
+            preserves_safety: false,
+        }
+    }
+
+    /// Apply the effect to the abstract stack, returning the values consumed with the top of the stack last
+    pub fn update_stack(&self, stack: &mut Vec<StackValue>) -> Result<Vec<StackValue>> {
         let mut is_safe = false;
+        let mut operands = Vec::default();
         match self {
             StackEffect::Normal {
                 remove_n,
@@ -110,8 +187,9 @@ impl StackEffect {
                 for _ in 0..*remove_n {
                     let stack_value = stack
                         .pop()
-                        .ok_or(anyhow!("Unbalanced stack - input program is malformed"))?;
+                        .ok_or(ChopError::malformed_stack("Unbalanced stack"))?;
                     is_safe |= *preserves_safety && *remove_n == 1 && stack_value.is_safe;
+                    operands.insert(0, stack_value);
                 }
                 if let Some(mut stack_value) = add {
                     stack_value.is_safe |= is_safe;
@@ -120,78 +198,166 @@ impl StackEffect {
             }
             StackEffect::Return => stack.clear(),
         }
-        Ok(())
+        Ok(operands)
     }
 
     // IMPORTANT!
     // This is the only place where we can detect unsupported instructions.
     // When adding a memory instruction it should also be added to the implementation of
     // InstructionType::from<&(Wast)Instruction>.
-    pub fn from_wast_instruction(instruction: &WastInstruction, local_types: &[DataType]) -> Self {
-        match instruction {
+    pub fn from_wast_instruction(
+        instruction: &WastInstruction,
+        locals: &LocalIndexSpace,
+        context: &ModuleContext,
+    ) -> Result<Self> {
+        Ok(match instruction {
             Return => Self::Return,
             End(_) | Block(_) | Br(_) => Self::new(0, None, false, false),
             LocalGet(index) => {
-                let (ty, is_safe) = type_and_safety_from_param(index, local_types);
+                let (ty, is_safe) = type_and_safety_from_param(index, locals)?;
                 Self::new(0, Some(ty), is_safe, true)
             }
             LocalTee(_) => Self::new(0, None, false, false),
-            I64Load(_) | I64Load32u(_) | I64ExtendI32U => {
-                Self::new(1, Some(DataType::I64), false, false)
+            GlobalGet(index) => {
+                let (_, global) = resolve_global(&context.globals, index)
+                    .ok_or(ChopError::undefined_reference(format_args!("global {}", show_index(index))))?;
+                Self::Normal {
+                    remove_n: 0,
+                    add: Some(StackValue { private: global.private, ..StackValue::new(global.ty) }),
+                    preserves_safety: false,
+                }
+            }
+            GlobalSet(index) => {
+                resolve_global(&context.globals, index)
+                    .ok_or(ChopError::undefined_reference(format_args!("global {}", show_index(index))))?;
+                Self::new(1, None, false, false)
             }
-            I64Const(_) => Self::new(0, Some(DataType::I64), false, false),
-            I32WrapI64 | I32Load(_) | I32Load16u(_) | I32Eqz => {
-                Self::new(1, Some(DataType::I32), false, true)
+            CallIndirect(call) => {
+                let ty = context.resolve_type_use(&call.ty)?;
+                if ty.results.len() > 1 {
+                    return Err(ChopError::unsupported_feature("Indirect calls with multiple results"));
+                }
+                // The table index is popped along with the arguments
+                Self::new(ty.params.len() + 1, ty.results.first().copied(), false, false)
+            }
+            I64Load(_) | I64Load8s(_) | I64Load8u(_) | I64Load16s(_) | I64Load16u(_)
+            | I64Load32s(_) | I64Load32u(_) | I64ExtendI32U => {
+                Self::new(1, Some(DataType::I64), false, false)
             }
-            I32Const(_) => Self::new(0, Some(DataType::I32), false, false),
+            I64Const(value) => Self::constant(DataType::I64, *value),
+            I32WrapI64 | I32Load(_) | I32Load8s(_) | I32Load8u(_) | I32Load16s(_)
+            | I32Load16u(_) | I32Eqz => Self::new(1, Some(DataType::I32), false, true),
+            F32Load(_) => Self::new(1, Some(DataType::F32), false, false),
+            F64Load(_) => Self::new(1, Some(DataType::F64), false, false),
+            I32Const(value) => Self::constant(DataType::I32, *value as i64),
             I32Mul | I32Add | I32Sub | I32Eq | F64Gt | F32Gt | I32GtU | I32GtS | I64GtU
             | I64GtS | I32LtU | I32LtS | I64LtU | I64LtS | I64Eq | I32Ne | I64Ne | I32Shl
-            | I32Xor | I32And => Self::new(2, Some(DataType::I32), false, false),
-            I64Mul | I64Add | I64Xor | I64Sub => Self::new(2, Some(DataType::I64), false, false),
-            I32Store(_) | I32Store8(_) | I32Store16(_) | I64Store(_) => {
+            | I32Xor | I32And | I32Or => Self::new(2, Some(DataType::I32), false, false),
+            I64Mul | I64Add | I64Xor | I64Sub | I64And | I64Or => {
+                Self::new(2, Some(DataType::I64), false, false)
+            }
+            I32Store(_) | I32Store8(_) | I32Store16(_) | I64Store(_) | I64Store8(_)
+            | I64Store16(_) | I64Store32(_) | F32Store(_) | F64Store(_) => {
                 Self::new(2, None, false, false)
             }
             Drop | BrIf(_) | LocalSet(_) => Self::new(1, None, false, false),
             F64Const(_) => Self::new(0, Some(DataType::F64), false, false),
             F32Const(_) => Self::new(0, Some(DataType::F32), false, false),
-            _ => panic!(
-                "Unsupported instruction read when producing StackEffect - {:?}",
-                instruction
-            ),
-        }
+            _ => return Err(ChopError::unsupported_instruction(instruction_name(instruction))),
+        })
     }
 
-    pub fn from_instruction(instruction: &Instruction, local_types: &[DataType]) -> Self {
-        Self::from_wast_instruction(instruction.instr, local_types)
+    pub fn from_instruction(
+        instruction: &Instruction,
+        locals: &LocalIndexSpace,
+        context: &ModuleContext,
+    ) -> Result<Self> {
+        Self::from_wast_instruction(instruction.instr, locals, context)
+            .map_err(|err| err.at(instruction.span))
     }
 }
 
-fn type_and_safety_from_param(index: &Index, local_types: &[DataType]) -> (DataType, bool) {
+fn type_and_safety_from_param(index: &Index, locals: &LocalIndexSpace) -> Result<(DataType, bool)> {
+    let undeclared = || ChopError::undefined_reference(format_args!("local {}", show_index(index)));
+    let index = locals.resolve(index).ok_or_else(undeclared)?;
+    let ty = locals.ty(index).ok_or_else(undeclared)?;
+    Ok((ty, locals.is_param(index)))
+}
+
+/// An index as it is written in the text format
+pub fn show_index(index: &Index) -> String {
     match index {
-        Index::Num(index, _) => {
-            let index = *index;
-            let safe = index_is_param(index);
-            let mut utx_locals = Vec::default();
-            utx_locals.extend_from_slice(&UTX_LOCALS);
-            utx_locals.extend_from_slice(local_types);
-            let ty = *utx_locals
-                .get(index as usize)
-                .expect("Indexed get to locals should use in bounds index");
-            (ty, safe)
-        }
-        // TODO - to be completely safe we need to check the type of id'd locals
-        // in case compiled code uses them (usually not)
-        Index::Id(id) => (DataType::I32, name_is_param(id.name())),
+        Index::Num(i, _) => i.to_string(),
+        Index::Id(id) => format!("${}", id.name()),
     }
 }
 
-fn name_is_param(name: &str) -> bool {
-    matches!(name, "tx" | "state")
+/// Names and types of the parameters and locals of a function, in index order
+#[derive(Clone, Default)]
+pub struct LocalIndexSpace {
+    params: Vec<(Option<String>, DataType)>,
+    locals: Vec<(Option<String>, DataType)>,
 }
 
-/// Assuming use in a function of the type (tx, state) -> ?
-pub fn index_is_param(index: u32) -> bool {
-    index < 3
+impl LocalIndexSpace {
+    pub fn new(
+        params: Vec<(Option<String>, DataType)>,
+        locals: Vec<(Option<String>, DataType)>,
+    ) -> Self {
+        Self { params, locals }
+    }
+
+    /// Index space of a transformed function, where the utx parameters precede the locals
+    pub fn with_utx_params(local_types: &[DataType]) -> Self {
+        Self::new(
+            UTX_PARAM_NAMES
+                .iter()
+                .zip(UTX_LOCALS)
+                .map(|(name, ty)| (Some(name.to_string()), ty))
+                .collect(),
+            local_types.iter().map(|ty| (None, *ty)).collect(),
+        )
+    }
+
+    /// The same parameters, followed by locals of the given types
+    pub fn with_locals(&self, local_types: &[DataType]) -> Self {
+        let locals = local_types
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| (self.locals.get(i).and_then(|(name, _)| name.clone()), *ty))
+            .collect();
+        Self::new(self.params.clone(), locals)
+    }
+
+    pub fn resolve(&self, index: &Index) -> Option<u32> {
+        match index {
+            Index::Num(index, _) => Some(*index),
+            Index::Id(id) => self
+                .params
+                .iter()
+                .chain(self.locals.iter())
+                .position(|(name, _)| name.as_deref() == Some(id.name()))
+                .map(|i| i as u32),
+        }
+    }
+
+    pub fn ty(&self, index: u32) -> Option<DataType> {
+        self.params
+            .iter()
+            .chain(self.locals.iter())
+            .nth(index as usize)
+            .map(|(_, ty)| *ty)
+    }
+
+    pub fn is_param(&self, index: u32) -> bool {
+        (index as usize) < self.params.len()
+    }
+
+    pub fn param_name(&self, index: u32) -> Option<&str> {
+        self.params
+            .get(index as usize)
+            .and_then(|(name, _)| name.as_deref())
+    }
 }
 
 /// To be used at some point inside of a scope
@@ -199,7 +365,7 @@ pub fn index_of_scope_end(instructions: &[Instruction]) -> Result<usize> {
     let mut scope_level = 1;
     for (i, instruction_with_text) in instructions.iter().enumerate() {
         if let InstructionType::Benign(BenignInstructionType::Block(block_instruction_type)) =
-            InstructionType::from(instruction_with_text)
+            InstructionType::try_from(instruction_with_text)?
         {
             scope_level += match block_instruction_type {
                 BlockInstructionType::End => -1,
@@ -208,10 +374,10 @@ pub fn index_of_scope_end(instructions: &[Instruction]) -> Result<usize> {
 
             match scope_level.cmp(&0) {
                 Ordering::Equal => return Ok(i),
-                Ordering::Less => return Err(anyhow!("Unbalanced scope delimiters")),
+                Ordering::Less => return Err(ChopError::malformed_stack("Unbalanced scope delimiters")),
                 Ordering::Greater => {}
             }
         }
     }
-    Err(anyhow!("Unbalanced scope delimiters"))
+    Err(ChopError::malformed_stack("Unbalanced scope delimiters"))
 }
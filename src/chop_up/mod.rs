@@ -1,11 +1,38 @@
+pub use annotation::parse_address_range;
+pub use diagnostic::Diagnostic;
+pub use error::ChopError;
 pub use function::IGNORE_FUNC_PREFIX;
-pub use transform::emit_transformed_wat;
-pub use instruction::{InstructionType, MemoryInstructionType};
+pub use global::synthetic_address;
+pub use options::{AbiLayout, ExplainLevel, FunctionFilter, ModuleFormat, ShadowStack, SplitOptions};
+pub use policy::{AlwaysSplit, SkipSafe, SplitDecision, SplitPolicy, SplitSite, StaticAnalysis};
+pub use source_map::{Culprit, MicrotransactionMapping, SourceMap};
+pub use output::{Microtransaction, TransformOutput};
+pub use transform::transform_wat;
+pub use validate::ValidationError;
+pub(crate) use annotation::is_nosplit;
+pub(crate) use function::Function;
+pub(crate) use global::resolve_global;
+pub(crate) use instruction::instruction_name;
+pub(crate) use module::ModuleContext;
+pub use instruction::{DataType, InstructionType, MemoryInstructionSubtype, MemoryInstructionType};
+pub use instruction_stream::{Scope, ScopeType, StackValue};
+pub use value_range::Bounds;
 
+mod annotation;
+mod diagnostic;
 mod emit;
+mod error;
 mod function;
+mod global;
 mod instruction;
 mod instruction_stream;
+mod module;
+mod options;
+mod output;
+mod policy;
+mod source_map;
 mod split;
 mod transform;
 mod utils;
+mod validate;
+mod value_range;
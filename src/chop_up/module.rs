@@ -0,0 +1,148 @@
+use wast::core::{FunctionType, ItemKind, ModuleField, TableKind, TypeDef, TypeUse};
+use wast::token::Index;
+
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::global::Global;
+use crate::chop_up::instruction_stream::show_index;
+use crate::chop_up::instruction::DataType;
+
+/// Module level definitions that instructions inside of functions may refer to
+#[derive(Clone, Default)]
+pub struct ModuleContext {
+    pub globals: Vec<Global>,
+    pub types: Vec<FuncType>,
+    pub table: Option<Table>,
+    /// Ids of all functions, imported ones included, in index space order
+    pub functions: Vec<Option<String>>,
+}
+
+impl ModuleContext {
+    pub fn from_module_fields(fields: &[ModuleField]) -> Result<Self> {
+        let types = fields
+            .iter()
+            .filter_map(|field| match field {
+                ModuleField::Type(ty) => match &ty.def {
+                    TypeDef::Func(func_type) => Some(
+                        FuncType::try_from(func_type)
+                            .map(|func_type| FuncType {
+                                name: ty.id.map(|id| id.name().into()),
+                                ..func_type
+                            })
+                            .map_err(|err| err.at(ty.span)),
+                    ),
+                    // Keep indices aligned even though these types can not be called
+                    _ => Some(Ok(FuncType::default())),
+                },
+                _ => None,
+            })
+            .collect::<Result<Vec<FuncType>>>()?;
+        let functions = fields
+            .iter()
+            .filter_map(|field| match field {
+                ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Func(_)) => {
+                    Some(import.item.id.map(|id| id.name().into()))
+                }
+                ModuleField::Func(func) => Some(func.id.map(|id| id.name().into())),
+                _ => None,
+            })
+            .collect();
+        Ok(Self {
+            functions,
+            globals: Global::from_module_fields(fields)?,
+            types,
+            table: Table::from_module_fields(fields)?,
+        })
+    }
+
+    /// Name of the function at `index`.
+    /// Functions without an id are named after their index, avoiding the ids of other functions.
+    pub fn function_name(&self, index: usize) -> String {
+        if let Some(Some(name)) = self.functions.get(index) {
+            return name.clone();
+        }
+        let mut name = format!("func_{index}");
+        while self.functions.iter().flatten().any(|id| *id == name) {
+            name.insert_str(0, "anon_");
+        }
+        name
+    }
+
+    /// Find the signature referenced by a type use, preferring the explicit index if present
+    pub fn resolve_type_use(&self, type_use: &TypeUse<FunctionType>) -> Result<FuncType> {
+        match (&type_use.index, &type_use.inline) {
+            (Some(Index::Num(i, _)), _) => self.types.get(*i as usize).cloned(),
+            (Some(Index::Id(id)), _) => self
+                .types
+                .iter()
+                .find(|ty| ty.name.as_deref() == Some(id.name()))
+                .cloned(),
+            (None, Some(inline)) => return FuncType::try_from(inline),
+            (None, None) => Some(FuncType::default()),
+        }
+        .ok_or_else(|| match &type_use.index {
+            Some(index) => ChopError::undefined_reference(format_args!("type {}", show_index(index))),
+            None => ChopError::undefined_reference("type"),
+        })
+    }
+}
+
+/// The first table of the module, which microtransaction entries are appended to
+#[derive(Clone, Debug)]
+pub struct Table {
+    pub name: Option<String>,
+    pub size: u32,
+}
+
+impl Table {
+    fn from_module_fields(fields: &[ModuleField]) -> Result<Option<Self>> {
+        for field in fields {
+            match field {
+                ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Table(_)) => {
+                    return Err(ChopError::unsupported_feature("Imported tables").at(import.span));
+                }
+                ModuleField::Table(table) => {
+                    return match &table.kind {
+                        TableKind::Normal { ty, init_expr: None } => Ok(Some(Self {
+                            name: table.id.map(|id| id.name().into()),
+                            size: ty.limits.min,
+                        })),
+                        _ => Err(ChopError::unsupported_feature(
+                            "Tables declared without limits or with initializers"
+                        )
+                        .at(table.span)),
+                    };
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FuncType {
+    pub name: Option<String>,
+    pub params: Vec<DataType>,
+    pub results: Vec<DataType>,
+}
+
+impl TryFrom<&FunctionType<'_>> for FuncType {
+    type Error = ChopError;
+
+    fn try_from(value: &FunctionType) -> Result<Self> {
+        Ok(Self {
+            name: None,
+            params: value
+                .params
+                .iter()
+                .map(|(_, _, ty)| DataType::try_from(*ty))
+                .collect::<Result<Vec<DataType>>>()?,
+            results: value
+                .results
+                .iter()
+                .map(|ty| DataType::try_from(*ty))
+                .collect::<Result<Vec<DataType>>>()?,
+        })
+    }
+}
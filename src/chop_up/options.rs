@@ -0,0 +1,253 @@
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::policy::{AlwaysSplit, SplitPolicy};
+
+/// Settings for splitting a module, shared by the command line and the library.
+///
+/// Start from [`SplitOptions::new`] with the size of the user defined state
+/// and chain the setters for anything that differs from the defaults:
+/// ```
+/// # use chop_up::{ExplainLevel, SkipSafe, SplitOptions};
+/// let options = SplitOptions::new(6)
+///     .policy(SkipSafe)
+///     .explain(ExplainLevel::Annotate)
+///     .only_functions(["bid"]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SplitOptions {
+    pub state_size: usize,
+    pub policy: Rc<dyn SplitPolicy>,
+    pub split_globals: bool,
+    pub explain: ExplainLevel,
+    pub abi: AbiLayout,
+    pub format: ModuleFormat,
+    pub functions: FunctionFilter,
+    /// Address ranges private to the transaction, accesses known to lie inside of one are not split
+    pub private_regions: Vec<Range<u64>>,
+    /// Names of globals pointing into memory private to the transaction, such as a shadow stack pointer
+    pub private_globals: Vec<String>,
+    pub shadow_stack: ShadowStack,
+    /// Where [`run_split`](crate::run_split) writes the source map, if anywhere
+    pub source_map_path: Option<String>,
+    /// Have [`run_split`](crate::run_split) write how the module grew to stderr
+    pub stats: bool,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        Self {
+            state_size: 0,
+            policy: Rc::new(AlwaysSplit),
+            split_globals: false,
+            explain: ExplainLevel::default(),
+            abi: AbiLayout::default(),
+            format: ModuleFormat::default(),
+            functions: FunctionFilter::default(),
+            private_regions: Vec::default(),
+            private_globals: Vec::default(),
+            shadow_stack: ShadowStack::default(),
+            source_map_path: None,
+            stats: false,
+        }
+    }
+}
+
+impl SplitOptions {
+    pub fn new(state_size: usize) -> Self {
+        Self {
+            state_size,
+            ..Self::default()
+        }
+    }
+
+    /// Bytes at the start of state reserved for the user defined state struct
+    pub fn state_size(mut self, state_size: usize) -> Self {
+        self.state_size = state_size;
+        self
+    }
+
+    /// Policy deciding which memory accesses to split at, [`AlwaysSplit`] by default
+    pub fn policy(mut self, policy: impl SplitPolicy + 'static) -> Self {
+        self.policy = Rc::new(policy);
+        self
+    }
+
+    /// Treat accesses to mutable globals as state accesses, yielding a synthetic address per global
+    pub fn split_globals(mut self, split_globals: bool) -> Self {
+        self.split_globals = split_globals;
+        self
+    }
+
+    pub fn explain(mut self, explain: ExplainLevel) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    pub fn abi(mut self, abi: AbiLayout) -> Self {
+        self.abi = abi;
+        self
+    }
+
+    pub fn format(mut self, format: ModuleFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Only split the named functions, emitting every other function as it is
+    pub fn only_functions<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.functions.only.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Emit the named functions as they are instead of splitting them
+    pub fn skip_functions<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.functions.skip.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Declare the addresses in `region` private to the transaction.
+    /// Accesses whose addresses can be shown to stay inside of the region are performed without a split.
+    pub fn private_region(mut self, region: Range<u64>) -> Self {
+        self.private_regions.push(region);
+        self
+    }
+
+    /// Declare the memory the named global points into private to the transaction, like a shadow stack.
+    /// Accesses at most 64 KiB away from the value of the global are performed without a split,
+    /// and so are accesses to the global itself.
+    pub fn private_global(mut self, name: impl Into<String>) -> Self {
+        self.private_globals.push(name.into());
+        self
+    }
+
+    /// How to find the shadow stack pointer, none is looked for by default
+    pub fn shadow_stack(mut self, shadow_stack: ShadowStack) -> Self {
+        self.shadow_stack = shadow_stack;
+        self
+    }
+
+    /// Write a JSON map from every microtransaction to the original code to `path` when splitting a file
+    pub fn source_map_path(mut self, path: impl Into<String>) -> Self {
+        self.source_map_path = Some(path.into());
+        self
+    }
+
+    /// Write how the module grew by splitting to stderr when splitting a file
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+/// How much explanation to add to the transformed module as comments
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExplainLevel {
+    #[default]
+    Off,
+    /// Explain the instructions added around splits
+    Annotate,
+    /// Also annotate every instruction carried over with its line in the input
+    Source,
+}
+
+/// The global holding the shadow stack pointer of C code compiled to WebAssembly.
+///
+/// Accesses relative to the shadow stack pointer are private to the transaction and performed without a split,
+/// and the pointer is saved in state across splits, after the store value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ShadowStack {
+    #[default]
+    Off,
+    /// Recognise the global clang uses, named `__stack_pointer` or moved by function prologues
+    Detect,
+    Global(String),
+}
+
+/// Format the transformed module is written in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ModuleFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+/// Layout of the utx struct the microtransactions fill in, in bytes.
+///
+/// Defaults to `struct utx { u32 addrs[7]; u8 log2lens[7]; u8 naddr; }`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbiLayout {
+    pub addrs_offset: u32,
+    pub log2lens_offset: u32,
+    pub naddr_offset: u32,
+    pub size: u32,
+    /// Capacity of the `addrs` and `log2lens` arrays
+    pub max_addrs: u32,
+}
+
+impl Default for AbiLayout {
+    fn default() -> Self {
+        Self {
+            addrs_offset: 0,
+            log2lens_offset: 28,
+            naddr_offset: 35,
+            size: 36,
+            max_addrs: 7,
+        }
+    }
+}
+
+impl AbiLayout {
+    /// Check that the utx has room for at least one address, as many as `naddr` can count,
+    /// and that every field lies within the utx without overlapping another
+    pub fn validate(&self) -> Result<()> {
+        if self.max_addrs == 0 || self.max_addrs > u8::MAX as u32 {
+            return Err(ChopError::abi_overflow(format_args!("A capacity of {} addresses", self.max_addrs)));
+        }
+        let field = |offset: u32, size: u32| offset as u64..offset as u64 + size as u64;
+        let fields = [
+            ("addrs", field(self.addrs_offset, 4 * self.max_addrs)),
+            ("log2lens", field(self.log2lens_offset, self.max_addrs)),
+            ("naddr", field(self.naddr_offset, 1)),
+        ];
+        for (i, (name, bytes)) in fields.iter().enumerate() {
+            if bytes.end > self.size as u64 {
+                return Err(ChopError::abi_overflow(format_args!(
+                    "Field {name} at {}..{} of a {} byte utx",
+                    bytes.start, bytes.end, self.size
+                )));
+            }
+            if let Some((other, other_bytes)) = fields[..i]
+                .iter()
+                .find(|(_, other_bytes)| other_bytes.start < bytes.end && bytes.start < other_bytes.end)
+            {
+                return Err(ChopError::abi_overflow(format_args!(
+                    "Field {name} at {}..{} overlapping {other} at {}..{}",
+                    bytes.start, bytes.end, other_bytes.start, other_bytes.end
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Functions selected for splitting, by name
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionFilter {
+    /// If not empty, only these functions are split
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl FunctionFilter {
+    pub fn selects(&self, name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|only| only == name))
+            && !self.skip.iter().any(|skip| skip == name)
+    }
+
+    /// Every function named by the filter
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.only.iter().chain(&self.skip).map(String::as_str)
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+use wast::parser::{parse, ParseBuffer};
+use wast::Wat;
+
+use crate::chop_up::error::Result;
+use crate::chop_up::options::ModuleFormat;
+use crate::chop_up::source_map::SourceMap;
+
+/// Everything produced by splitting a module
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TransformOutput {
+    /// The transformed module in the text format
+    pub module: String,
+    /// Microtransaction functions in table order
+    pub microtransactions: Vec<Microtransaction>,
+    /// Number of splits made in each transaction function, by function name
+    pub split_counts: BTreeMap<String, usize>,
+    /// Bytes of state past the user defined state used by the transformation, the largest of all splits
+    pub state_usage: usize,
+    /// Memory accesses performed without a split before them, counted for every microtransaction they are emitted in
+    pub unsplit_accesses: usize,
+    /// Positions in the body of every transaction function of the accesses whose addresses are yielded in a utx
+    pub declared_accesses: BTreeMap<String, BTreeSet<usize>>,
+    /// Instructions saving and restoring the stack, locals and store value across splits, by microtransaction name
+    pub state_instructions: BTreeMap<String, usize>,
+    pub source_map: SourceMap,
+    /// Parts of the input that are not carried over to the transformed module
+    pub warnings: Vec<String>,
+}
+
+impl TransformOutput {
+    /// The most microtransactions a single run of the transaction function may be split into
+    pub fn max_chain_length(&self, function: &str) -> usize {
+        let mut chain_lengths = BTreeMap::default();
+        self.microtransactions
+            .iter()
+            .find(|microtransaction| microtransaction.name == function)
+            .map(|entry| self.chain_length(entry, &mut chain_lengths))
+            .unwrap_or(0)
+    }
+
+    /// Length of the longest chain starting at `microtransaction`.
+    /// Splits only ever continue further into the function, so chains can not loop.
+    fn chain_length(&self, microtransaction: &Microtransaction, chain_lengths: &mut BTreeMap<usize, usize>) -> usize {
+        if let Some(&length) = chain_lengths.get(&microtransaction.table_index) {
+            return length;
+        }
+        let length = 1 + microtransaction
+            .successors
+            .iter()
+            .filter_map(|&index| {
+                self.microtransactions
+                    .iter()
+                    .find(|successor| successor.table_index == index)
+            })
+            .map(|successor| self.chain_length(successor, chain_lengths))
+            .max()
+            .unwrap_or(0);
+        chain_lengths.insert(microtransaction.table_index, length);
+        length
+    }
+
+    /// The transformed module in the given format
+    pub fn encode(&self, format: ModuleFormat) -> Result<Vec<u8>> {
+        match format {
+            ModuleFormat::Text => Ok(self.module.clone().into_bytes()),
+            ModuleFormat::Binary => {
+                let buffer = ParseBuffer::new(&self.module)?;
+                let mut wat = parse::<Wat>(&buffer)?;
+                Ok(wat.encode()?)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Microtransaction {
+    pub name: String,
+    pub table_index: usize,
+    /// The transaction function the microtransaction was split from
+    pub function: String,
+    /// Bytes of state past the user defined state holding the stack and locals restored on entry,
+    /// including the 8 bytes reserved for a store value
+    pub saved_state: usize,
+    /// Table indices of the microtransactions this one may continue with, empty if it always ends the transaction
+    pub successors: Vec<usize>,
+}
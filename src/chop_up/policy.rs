@@ -0,0 +1,112 @@
+use std::fmt::Debug;
+use std::ops::Range;
+
+use wast::core::Instruction as WastInstruction;
+
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::instruction::MemoryInstructionType;
+use crate::chop_up::instruction_stream::{Scope, StackValue};
+
+/// What to do at a memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitDecision {
+    /// End the microtransaction, yielding the address of the access to the runtime before it is performed
+    Split,
+    /// Perform the access without yielding its address
+    NoSplit,
+    /// Declare the address of the access along with the split before it, and perform it without a split of its own.
+    /// Only accesses to constant addresses following a split in the same straight-line code at the top level
+    /// can be declared ahead, while the utx has room for their addresses. Any other access is split instead.
+    BatchWithNext,
+}
+
+/// A memory access the transformer may split at
+#[derive(Debug)]
+pub struct SplitSite<'a> {
+    /// Name of the function containing the access, generated for split functions
+    pub function: &'a str,
+    pub instruction: &'a WastInstruction<'a>,
+    /// The instruction as it is written in the input
+    pub text: &'a str,
+    pub access: MemoryInstructionType,
+    /// Abstract stack before the access, the top of the stack last
+    pub stack: &'a [StackValue],
+    /// Blocks enclosing the access, the innermost last
+    pub scopes: &'a [Scope],
+}
+
+impl SplitSite<'_> {
+    /// The value holding the address of the access
+    pub fn address(&self) -> Result<&StackValue> {
+        let depth = match self.access {
+            MemoryInstructionType::Load { .. } => 1,
+            MemoryInstructionType::Store { .. } => 2,
+        };
+        self.stack
+            .len()
+            .checked_sub(depth)
+            .map(|i| &self.stack[i])
+            .ok_or(ChopError::malformed_stack("Memory access with too few values on the stack"))
+    }
+
+    /// The bytes the access may touch, if the address is bounded
+    pub fn address_range(&self) -> Result<Option<Range<u64>>> {
+        let address = self.address()?;
+        Ok(address
+            .bounds
+            .and_then(|bounds| bounds.unsigned(address.ty))
+            .map(|addresses| {
+                let start = addresses.start.saturating_add(self.access.offset());
+                let end = addresses.end.saturating_add(self.access.offset());
+                start..end.saturating_add(self.access.size() as u64 - 1)
+            }))
+    }
+}
+
+/// Decides whether to split at each memory access of a transaction.
+///
+/// Implement this to try out other heuristics than the built-in policies,
+/// and pass it to [`SplitOptions::policy`](crate::SplitOptions::policy).
+pub trait SplitPolicy: Debug {
+    fn decide(&self, site: &SplitSite) -> Result<SplitDecision>;
+}
+
+/// Split at every memory access
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AlwaysSplit;
+
+impl SplitPolicy for AlwaysSplit {
+    fn decide(&self, _: &SplitSite) -> Result<SplitDecision> {
+        Ok(SplitDecision::Split)
+    }
+}
+
+/// Skip splits for loads through addresses derived from the function arguments,
+/// which are taken to point to memory private to the transaction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SkipSafe;
+
+impl SplitPolicy for SkipSafe {
+    fn decide(&self, site: &SplitSite) -> Result<SplitDecision> {
+        let is_safe_load = matches!(site.access, MemoryInstructionType::Load { .. }) && site.address()?.is_safe;
+        Ok(if is_safe_load {
+            SplitDecision::NoSplit
+        } else {
+            SplitDecision::Split
+        })
+    }
+}
+
+/// Skip splits like [`SkipSafe`], and batch accesses to constant addresses with the split before them,
+/// as their addresses are known before the transaction is run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaticAnalysis;
+
+impl SplitPolicy for StaticAnalysis {
+    fn decide(&self, site: &SplitSite) -> Result<SplitDecision> {
+        if site.address()?.constant().is_some() {
+            return Ok(SplitDecision::BatchWithNext);
+        }
+        SkipSafe.decide(site)
+    }
+}
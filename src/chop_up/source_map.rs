@@ -0,0 +1,65 @@
+use std::ops::Range;
+
+use serde::Serialize;
+
+/// Links every microtransaction of a split module back to the function it was split from
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SourceMap {
+    pub microtransactions: Vec<MicrotransactionMapping>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MicrotransactionMapping {
+    /// Name of the generated function
+    pub name: String,
+    /// Index of the microtransaction in the table, as returned by the microtransaction before it
+    pub table_index: usize,
+    /// Name of the original function
+    pub function: String,
+    /// Positions in the body of the original function of the instructions this microtransaction executes.
+    /// Splits inside of blocks skip over the rest of the block, leaving more than one range.
+    pub instructions: Vec<Range<usize>>,
+    /// The access performed at the start of the microtransaction, `None` for the entry of a function
+    pub culprit: Option<Culprit>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Culprit {
+    pub instruction: String,
+    /// Line of the instruction in the input, starting at 1
+    pub line: usize,
+}
+
+impl SourceMap {
+    /// Start mapping a new microtransaction
+    pub fn add(&mut self, name: &str, function: &str, culprit: Option<Culprit>) {
+        self.microtransactions.push(MicrotransactionMapping {
+            name: name.into(),
+            table_index: 0,
+            function: function.into(),
+            instructions: Vec::default(),
+            culprit,
+        });
+    }
+
+    /// Name of the original function a microtransaction was split from
+    pub fn function_of(&self, name: &str) -> Option<&str> {
+        self.microtransactions
+            .iter()
+            .find(|mapping| mapping.name == name)
+            .map(|mapping| mapping.function.as_str())
+    }
+
+    /// Record that the microtransaction executes the instruction at `position`.
+    /// The instruction causing a split is recorded in both microtransactions,
+    /// as its address is computed before the split and its access performed after.
+    pub fn record_instruction(&mut self, name: &str, position: usize) {
+        let Some(mapping) = self.microtransactions.iter_mut().find(|mapping| mapping.name == name) else {
+            return;
+        };
+        match mapping.instructions.last_mut() {
+            Some(range) if range.end == position => range.end += 1,
+            _ => mapping.instructions.push(position..position + 1),
+        }
+    }
+}
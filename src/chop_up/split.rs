@@ -1,20 +1,25 @@
+use wast::core::Instruction as WastInstruction;
+
 use crate::chop_up::emit::WatEmitter;
-use crate::chop_up::instruction::{DataType, MemoryInstructionType};
+use crate::chop_up::error::{ChopError, Result};
+use crate::chop_up::instruction::{DataType, InstructionType, MemoryInstructionType};
 use crate::chop_up::instruction_stream::index_of_scope_end;
 use crate::chop_up::instruction_stream::{Instruction, Scope, ScopeType, StackValue};
-use crate::chop_up::transform::{handle_instructions, setup_func};
+use crate::chop_up::policy::SplitDecision;
+use crate::chop_up::source_map::Culprit;
+use crate::chop_up::transform::{decide_split, handle_instructions, setup_func, split_site};
 #[allow(unused_imports)] // This is due to a bug in my linter...
-use crate::chop_up::utils::{ADDRESS_LOCAL_NAME, STACK_JUGGLER_NAME};
-use anyhow::Result;
+use crate::chop_up::utils::{with_offset, ADDRESS_LOCAL_NAME, STACK_JUGGLER_NAME};
 
 pub fn setup_split<'a>(
     base_name: &str,
     split_count: usize,
     instructions: &'a [Instruction],
     locals: &[DataType],
-    culprit_instruction_with_index: (&Instruction, MemoryInstructionType, usize),
+    culprit_instruction_with_index: (&Instruction, SplitCulprit, usize),
     transformer: &mut WatEmitter,
 ) -> Result<Vec<Split<'a>>> {
+    let culprit_scopes_empty = culprit_instruction_with_index.0.scopes.is_empty();
     let mut deferred_splits = Vec::default();
     if let Some(new_split) = handle_pre_split(
         base_name,
@@ -23,10 +28,10 @@ pub fn setup_split<'a>(
         locals,
         split_count,
         transformer,
-    ) {
+    )? {
         deferred_splits.push(new_split);
     }
-    if culprit_instruction_with_index.0.scopes.is_empty() {
+    if culprit_scopes_empty {
         // Split happens in top-level scope
         transformer.emit_end_func();
     } else {
@@ -47,35 +52,73 @@ pub fn setup_split<'a>(
 
 pub fn handle_pre_split<'a>(
     base_name: &str,
-    culprit_instruction_with_index: (&Instruction, MemoryInstructionType, usize),
+    culprit_instruction_with_index: (&Instruction, SplitCulprit, usize),
     instructions: &'a [Instruction],
     locals: &[DataType],
     split_count: usize,
     transformer: &mut WatEmitter,
-) -> Option<Split<'a>> {
+) -> Result<Option<Split<'a>>> {
     let (culprit, culprit_type, culprit_index) = culprit_instruction_with_index;
-    let (pre_split_instructions, to_remove) = match culprit_type {
-        MemoryInstructionType::Load { offset, .. } => {
+    if let SplitCulprit::Memory(
+        MemoryInstructionType::Load { offset, .. } | MemoryInstructionType::Store { offset, .. },
+    ) = culprit_type
+    {
+        // The address passed in the utx is a 32 bit value
+        if offset > u32::MAX as u64 {
+            return Err(ChopError::abi_overflow(format_args!("Memory offset {offset}")).at(culprit.span));
+        }
+    }
+    // Check if a split has already been created for this instruction,
+    // if so return existing index
+    // else return new function index (derived from the current function count)
+    let existing_index = transformer
+        .utx_function_names
+        .iter()
+        .position(|(address, _)| culprit_index == *address);
+    let index = existing_index.unwrap_or(transformer.utx_function_names.len()) + transformer.table_base();
+    let name = match existing_index {
+        Some(existing_index) => transformer.utx_function_names[existing_index].1.clone(),
+        None => format!("{base_name}_{split_index}", split_index = split_count + 1),
+    };
+    let declared = if culprit.scopes.is_empty() {
+        declare_ahead(&name, instructions, transformer)?
+    } else {
+        Vec::default()
+    };
+    let function = transformer
+        .source_map
+        .function_of(base_name)
+        .unwrap_or(base_name)
+        .to_string();
+    transformer
+        .declared_accesses
+        .entry(function.clone())
+        .or_default()
+        .extend(std::iter::once(culprit.position).chain(declared.iter().map(|&(position, _)| position)));
+
+    let address_slot = transformer.abi.addrs_offset;
+    let (pre_split_instructions, to_remove) = match &culprit_type {
+        SplitCulprit::Memory(MemoryInstructionType::Load { offset, .. }) => {
             let set_address = format!("local.set ${ADDRESS_LOCAL_NAME}");
             let get_address = format!("local.get ${ADDRESS_LOCAL_NAME}");
             let offset_const = format!("i32.const {offset}");
             (
                 vec![
-                    (set_address, Some("Save address for load".into())),
-                    ("local.get $utx".into(), None),
-                    (get_address, None),
-                    (offset_const, Some("Convert =offset to value".into())),
-                    ("i32.add".into(), None),
-                    ("i32.store".into(), None),
+                    (set_address, Some("Save address for load".into()), false),
+                    ("local.get $utx".into(), None, false),
+                    (get_address, None, false),
+                    (offset_const, Some("Convert =offset to value".into()), false),
+                    ("i32.add".into(), None, false),
+                    (with_offset("i32.store", address_slot), None, false),
                 ],
                 1,
             )
         }
-        MemoryInstructionType::Store {
+        SplitCulprit::Memory(MemoryInstructionType::Store {
             ty,
             offset,
             subtype: _,
-        } => {
+        }) => {
             let ty = ty.as_str();
             let stack_juggler_local_name = format!("{ty}_{STACK_JUGGLER_NAME}");
             let set_value = format!("local.set ${stack_juggler_local_name}");
@@ -89,34 +132,59 @@ pub fn handle_pre_split<'a>(
             let offset_const = format!("i32.const {offset}");
             (
                 vec![
-                    (set_value, Some("Save value for store".into())),
-                    (set_address, Some("Save address for store".into())),
-                    ("local.get $state".into(), None),
-                    (get_value, None),
+                    (set_value, Some("Save value for store".into()), false),
+                    (set_address, Some("Save address for store".into()), false),
+                    ("local.get $state".into(), None, true),
+                    (get_value, None, true),
                     (
                         store_data_type,
                         Some(format!(
                             "First {n} bytes reserved for user defined state struct",
                             n = transformer.state_base
                         )),
+                        true,
                     ),
-                    ("local.get $utx".into(), None),
-                    (get_address, None),
-                    (offset_const, Some("Convert =offset to value".into())),
-                    ("i32.add".into(), None),
-                    ("i32.store".into(), None),
+                    ("local.get $utx".into(), None, false),
+                    (get_address, None, false),
+                    (offset_const, Some("Convert =offset to value".into()), false),
+                    ("i32.add".into(), None, false),
+                    (with_offset("i32.store", address_slot), None, false),
                 ],
                 2,
             )
         }
+        SplitCulprit::Global { address, .. } => (
+            vec![
+                (
+                    "local.get $utx".into(),
+                    Some("Save synthetic address for global".into()),
+                    false,
+                ),
+                (format!("i32.const {address}"), None, false),
+                (with_offset("i32.store", address_slot), None, false),
+            ],
+            0,
+        ),
     };
 
-    for (pre_split_instr, annotation) in pre_split_instructions {
-        transformer.emit_instruction(&pre_split_instr, annotation);
+    // Instructions marked as saving state carry the store value over to the next microtransaction
+    for (pre_split_instr, annotation, saves_state) in pre_split_instructions {
+        if saves_state {
+            transformer.emit_state_instruction(&pre_split_instr, annotation);
+        } else {
+            transformer.emit_instruction(&pre_split_instr, annotation);
+        }
+    }
+    for (i, (_, address)) in declared.iter().enumerate() {
+        let slot = address_slot + 4 * (i as u32 + 1);
+        transformer.emit_instruction("local.get $utx", Some("Declare address of batched access".into()));
+        transformer.emit_instruction(&format!("i32.const {address}"), None);
+        transformer.emit_instruction(&with_offset("i32.store", slot), None);
     }
-    transformer.emit_instruction("local.get $utx", Some("Save naddr = 1".into()));
-    transformer.emit_instruction("i32.const 1", None);
-    transformer.emit_instruction("i32.store8 offset=35", None);
+    let naddr = declared.len() + 1;
+    transformer.emit_instruction("local.get $utx", Some(format!("Save naddr = {naddr}")));
+    transformer.emit_instruction(&format!("i32.const {naddr}"), None);
+    transformer.emit_instruction(&with_offset("i32.store8", transformer.abi.naddr_offset), None);
 
     let stack_start = culprit
         .scopes
@@ -124,44 +192,107 @@ pub fn handle_pre_split<'a>(
         .map(|scope| scope.stack_start)
         .unwrap_or(0);
     let stack = &culprit.stack[..culprit.stack.len() - to_remove];
-    transformer.emit_save_stack_and_locals(
-        stack,
-        stack_start,
-        false,
-        locals,
-    );
+    let saved_state = transformer.emit_save_stack_and_locals(stack, stack_start, locals);
+    transformer.emit_save_shadow_stack_pointer();
+    let state_end = transformer.state_base + saved_state;
+    if state_end > u32::MAX as usize {
+        return Err(ChopError::abi_overflow(format_args!(
+            "Values saved across the split end at state offset {state_end}"
+        ))
+        .at(culprit.span));
+    }
 
-    // Check if a split has already been created for this instruction,
-    // if so return existing index
-    // else return new function index (derived from the current function count)
-    let existing_index = transformer
-        .utx_function_names
-        .iter()
-        .position(|(address, _)| culprit_index == *address);
-    let index = existing_index.unwrap_or(transformer.utx_function_names.len()) + 1;
+    let saved = transformer.saved_state.entry(name.clone()).or_default();
+    *saved = saved_state.max(*saved);
+    let successors = transformer.successors.entry(base_name.to_string()).or_default();
+    if !successors.contains(&name) {
+        successors.push(name.clone());
+    }
     transformer.emit_instruction(
         &format!("i32.const {index}"),
         Some("Return index to next microtransaction".into()),
     );
 
     if existing_index.is_none() {
-        let name = format!("{base_name}_{split_index}", split_index = split_count + 1);
         transformer
             .utx_function_names
             .push((culprit_index, name.clone()));
-        Some(Split {
+        let mapped_culprit = Culprit {
+            instruction: culprit.raw_text.clone(),
+            line: culprit.index + 1,
+        };
+        transformer.source_map.add(&name, &function, Some(mapped_culprit));
+        transformer.source_map.record_instruction(&name, culprit.position);
+        Ok(Some(Split {
             name,
             culprit_type,
             instructions,
             locals: locals.to_vec(),
             saved_stack: stack.to_vec(),
             scopes: culprit.scopes.to_vec(),
-        })
+            declared: declared.into_iter().map(|(position, _)| position).collect(),
+        }))
     } else {
-        None
+        Ok(None)
     }
 }
 
+/// Find the accesses after a split to declare along with it: those the policy batches,
+/// following the split in straight-line code up to the next split, and to constant addresses.
+/// Returns their positions and addresses.
+fn declare_ahead(
+    function: &str,
+    instructions: &[Instruction],
+    transformer: &WatEmitter,
+) -> Result<Vec<(usize, u32)>> {
+    let mut declared = Vec::default();
+    for instruction in instructions {
+        if !instruction.scopes.is_empty() || is_control(instruction.instr) {
+            break;
+        }
+        let access = match InstructionType::try_from(instruction)? {
+            InstructionType::Memory(access) => access,
+            InstructionType::Global(_) => break,
+            InstructionType::Benign(_) => continue,
+        };
+        // The split itself takes the first slot
+        let has_slot = declared.len() + 1 < transformer.abi.max_addrs as usize;
+        let site = split_site(function, instruction, access);
+        match decide_split(&site, instruction.span, transformer)? {
+            SplitDecision::NoSplit => {}
+            SplitDecision::BatchWithNext if has_slot => {
+                let address = site
+                    .address_range()?
+                    .filter(|addresses| addresses.end - addresses.start == access.size() as u64)
+                    .and_then(|addresses| u32::try_from(addresses.start).ok());
+                match address {
+                    Some(address) => declared.push((instruction.position, address)),
+                    None => break,
+                }
+            }
+            SplitDecision::Split | SplitDecision::BatchWithNext => break,
+        }
+    }
+    Ok(declared)
+}
+
+/// Whether control may leave the straight-line code at the instruction
+fn is_control(instruction: &WastInstruction) -> bool {
+    matches!(
+        instruction,
+        WastInstruction::Block(_)
+            | WastInstruction::Loop(_)
+            | WastInstruction::If(_)
+            | WastInstruction::Else(_)
+            | WastInstruction::End(_)
+            | WastInstruction::Br(_)
+            | WastInstruction::BrIf(_)
+            | WastInstruction::BrTable(_)
+            | WastInstruction::Return
+            | WastInstruction::Unreachable
+    )
+}
+
 pub fn handle_split<'a>(
     split: Split<'a>,
     transformer: &mut WatEmitter,
@@ -171,11 +302,13 @@ pub fn handle_split<'a>(
         split.instructions,
         &split.locals,
         transformer,
-    );
+    )?;
+    transformer.declared = split.declared.iter().copied().collect();
     transformer.emit_restore_locals(
         &split.locals,
         &split.saved_stack,
     );
+    transformer.emit_restore_shadow_stack_pointer();
     if split.scopes.is_empty() {
         transformer.emit_restore_stack(&split.saved_stack, 0, split.saved_stack.len());
     } else {
@@ -190,29 +323,24 @@ pub fn handle_split<'a>(
                         scope.stack_start,
                     );
                     curr_stack_base = scope.stack_start;
-                    let instruction = if let Some(name) = &scope.name {
-                        format!("(block ${name}")
-                    } else {
-                        "(block".into()
-                    };
-                    transformer.emit_instruction(&instruction, None);
+                    transformer.emit_instruction(&scope.opening_text(false), None);
                     transformer.current_scope_level += 1;
                 }
             }
         }
         transformer.emit_restore_stack(&split.saved_stack, curr_stack_base, split.saved_stack.len());
     }
-    let instructions: Vec<(String, Option<String>)> = match split.culprit_type {
-        MemoryInstructionType::Load { ty, subtype, .. } => {
+    let instructions: Vec<(String, Option<String>, bool)> = match split.culprit_type {
+        SplitCulprit::Memory(MemoryInstructionType::Load { ty, subtype, .. }) => {
             let subtype_str = subtype.map(|ty| ty.as_str()).unwrap_or("");
             let load_data_type = format!("{}.load{subtype_str}", ty.as_str());
             vec![
-                ("local.get $utx".into(), Some("Restore load address".into())),
-                ("i32.load".into(), None),
-                (load_data_type, None),
+                ("local.get $utx".into(), Some("Restore load address".into()), false),
+                (with_offset("i32.load", transformer.abi.addrs_offset), None, false),
+                (load_data_type, None, false),
             ]
         }
-        MemoryInstructionType::Store { ty, subtype, .. } => {
+        SplitCulprit::Memory(MemoryInstructionType::Store { ty, subtype, .. }) => {
             let subtype_str = subtype.map(|ty| ty.as_str()).unwrap_or("");
             let store_data_type = format!("{}.store{subtype_str}", ty.as_str());
             let load_data_type = format!(
@@ -224,20 +352,29 @@ pub fn handle_split<'a>(
                 (
                     "local.get $utx".into(),
                     Some("Restore store address".into()),
+                    false,
                 ),
-                ("i32.load".into(), None),
+                (with_offset("i32.load", transformer.abi.addrs_offset), None, false),
                 (
                     "local.get $state".into(),
                     Some("Restore store value".into()),
+                    true,
                 ),
-                (load_data_type, None),
-                (store_data_type, None),
+                (load_data_type, None, true),
+                (store_data_type, None, false),
             ]
         }
+        SplitCulprit::Global { instruction, .. } => {
+            vec![(instruction, Some("Perform global access".into()), false)]
+        }
     };
 
-    for (post_split_instr, annotation) in instructions {
-        transformer.emit_instruction(&post_split_instr, annotation);
+    for (post_split_instr, annotation, restores_state) in instructions {
+        if restores_state {
+            transformer.emit_state_instruction(&post_split_instr, annotation);
+        } else {
+            transformer.emit_instruction(&post_split_instr, annotation);
+        }
     }
 
     handle_instructions(
@@ -249,12 +386,21 @@ pub fn handle_split<'a>(
     )
 }
 
+/// The access that caused a split, to be performed at the start of the next microtransaction
+#[derive(Clone)]
+pub enum SplitCulprit {
+    Memory(MemoryInstructionType),
+    Global { address: u32, instruction: String },
+}
+
 #[derive(Clone)]
 pub struct Split<'a> {
     name: String,
-    culprit_type: MemoryInstructionType,
+    culprit_type: SplitCulprit,
     instructions: &'a [Instruction<'a>],
     locals: Vec<DataType>,
     saved_stack: Vec<StackValue>,
     scopes: Vec<Scope>,
+    /// Positions of the accesses after the culprit declared along with it
+    declared: Vec<usize>,
 }
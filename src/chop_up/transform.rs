@@ -1,82 +1,268 @@
-use std::io::Write;
+use std::collections::BTreeMap;
 
-use anyhow::Result;
-use wast::core::{Func, ModuleField};
+use wast::core::{Func, GlobalKind, ItemKind, MemoryKind, MemoryType, ModuleField};
+use wast::token::Span;
 use wast::Wat;
 
+use crate::chop_up::annotation::{is_nosplit, Annotations};
 use crate::chop_up::emit::WatEmitter;
+use crate::chop_up::error::{ChopError, Result};
 use crate::chop_up::function::Function;
+use crate::chop_up::global::{find_shadow_stack_pointer, global_index, resolve_global, synthetic_address};
 use crate::chop_up::instruction::{
-    BenignInstructionType, BlockInstructionType, DataType, InstructionType,
+    BenignInstructionType, BlockInstructionType, DataType, InstructionType, MemoryInstructionType,
 };
 use crate::chop_up::instruction_stream::Instruction;
-use crate::chop_up::split::{handle_split, setup_split, Split};
+use crate::chop_up::module::ModuleContext;
+use crate::chop_up::options::{ShadowStack, SplitOptions};
+use crate::chop_up::output::{Microtransaction, TransformOutput};
+use crate::chop_up::policy::{SplitDecision, SplitSite};
+use crate::chop_up::split::{handle_split, setup_split, Split, SplitCulprit};
 use crate::chop_up::utils::{count_parens, get_line_from_offset, MODULE_MEMBER_INDENT};
+use crate::chop_up::validate::validate_wat;
+use crate::chop_up::value_range::PRIVATE_OFFSET_LIMIT;
 use crate::extract_module_fields;
 
-pub fn emit_transformed_wat(
+/// Split every transaction function of a module selected by the options into microtransactions.
+/// The transformed module is validated before it is returned,
+/// failing with `ChopError::Invalid` if it is not valid WebAssembly.
+pub fn transform_wat(
     wat: &Wat,
     lines: &[&str],
-    writer: &mut dyn Write,
-    skip_safe_splits: bool,
-    state_size: usize,
-    explain: bool,
-) -> Result<()> {
-    let mut transformer = WatEmitter::new(writer, state_size, skip_safe_splits, explain);
+    options: &SplitOptions,
+) -> Result<TransformOutput> {
+    options.abi.validate()?;
+    let fields = extract_module_fields(wat)?;
+    let mut context = ModuleContext::from_module_fields(fields)?;
+    let mut warnings = Vec::default();
+    for name in &options.private_globals {
+        match context.globals.iter_mut().find(|global| global.name.as_deref() == Some(name)) {
+            Some(global) => global.private = true,
+            None => warnings.push(format!("No global named ${name} to treat as private")),
+        }
+    }
+    let shadow_stack_pointer = match &options.shadow_stack {
+        ShadowStack::Detect => find_shadow_stack_pointer(fields, &context.globals),
+        ShadowStack::Global(name) => {
+            let global = context
+                .globals
+                .iter()
+                .position(|global| global.name.as_deref() == Some(name));
+            if global.is_none() {
+                warnings.push(format!("No global named ${name} to use as the shadow stack pointer"));
+            }
+            global.map(|i| i as u32)
+        }
+        ShadowStack::Off => None,
+    };
+    if let Some(index) = shadow_stack_pointer {
+        context.globals[index as usize].private = true;
+    }
+    let mut output = String::new();
+    let mut transformer = WatEmitter::new(&mut output, options, context.clone());
+    if let Some(index) = shadow_stack_pointer {
+        let global = &context.globals[index as usize];
+        let reference = global.name.as_ref().map(|name| format!("${name}")).unwrap_or(index.to_string());
+        transformer.set_shadow_stack_pointer(reference, global.ty);
+    }
+    transformer.annotations = Annotations::from_lines(lines)?;
+    transformer
+        .annotations
+        .private_regions
+        .extend(options.private_regions.iter().cloned());
     transformer.emit_module();
 
     let mut functions = Vec::default();
     let mut module_members = Vec::default();
-    for field in extract_module_fields(wat)? {
+    let mut table_count = 0;
+    let mut function_index = 0;
+    for field in fields {
         match field {
-            ModuleField::Func(func) => functions.push(extract_function(func, lines)?),
-            ModuleField::Export(export) => module_members.push(export.span.offset()),
-            ModuleField::Type(ty) => module_members.push(ty.span.offset()),
-            ModuleField::Global(global) => module_members.push(global.span.offset()),
-            ModuleField::Data(data) => module_members.push(data.span.offset()),
+            ModuleField::Func(func) => {
+                functions.push(extract_function(func, function_index, lines, &context)?);
+                function_index += 1;
+            }
+            // Imported globals keep their place in the global index space,
+            // emitted ahead of the functions as imports must come before any definition
+            ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Global(_)) => {
+                emit_module_member(lines, import.span.offset(), &mut transformer);
+            }
+            ModuleField::Global(global) if matches!(global.kind, GlobalKind::Import(_)) => {
+                emit_module_member(lines, global.span.offset(), &mut transformer);
+            }
+            // Imported functions come first in the function index space
+            ModuleField::Import(import) => {
+                if matches!(import.item.kind, ItemKind::Func(_)) {
+                    function_index += 1;
+                }
+                warnings.push(format!(
+                    "Import {}.{} is not carried over to the transformed module",
+                    import.module, import.field
+                ));
+            }
+            ModuleField::Export(export) => module_members.push(ModuleMember::Line(export.span.offset())),
+            ModuleField::Type(ty) => module_members.push(ModuleMember::Line(ty.span.offset())),
+            ModuleField::Global(global) => module_members.push(ModuleMember::Line(global.span.offset())),
+            ModuleField::Data(data) => module_members.push(ModuleMember::Line(data.span.offset())),
+            ModuleField::Elem(elem) => module_members.push(ModuleMember::Line(elem.span.offset())),
+            ModuleField::Table(table) => {
+                // The first table is re-emitted in its place with room for the microtransaction entries
+                module_members.push(match table_count {
+                    0 => ModuleMember::FuncrefTable,
+                    _ => ModuleMember::Line(table.span.offset()),
+                });
+                table_count += 1;
+            }
+            ModuleField::Memory(memory) => {
+                // Memories are replaced by the 10 page memory emitted at the end of the module
+                let fits = memory.exports.names.is_empty()
+                    && matches!(
+                        memory.kind,
+                        MemoryKind::Normal(MemoryType::B32 { limits, shared: false }) if limits.min <= 10
+                    );
+                if !fits {
+                    warnings.push("Memory is replaced by an unshared 32 bit memory of 10 pages".into());
+                }
+            }
+            ModuleField::Start(_) => {
+                warnings.push("Start function is not carried over to the transformed module".into());
+            }
             _ => { /* Other module fields might need to be handled at a later date */ }
         }
     }
 
+    for name in options.functions.names() {
+        if !functions.iter().any(|func| func.name == name) {
+            warnings.push(format!("No function named ${name} to select for splitting"));
+        }
+    }
+
     let mut splits = Vec::default();
     for func in &functions {
-        let mut new_splits = handle_top_level_func(func, &mut transformer)?;
+        let mut new_splits = handle_top_level_func(func, options.functions.selects(&func.name), &mut transformer)
+            .map_err(|err| err.in_function(&func.name))?;
         splits.append(&mut new_splits);
     }
 
     while !splits.is_empty() {
         // Creating a split may create new splits,
         // therefore keep this loop going until none more remain
-        splits = splits
-            .drain(..)
-            .flat_map(|split| handle_split(split, &mut transformer).unwrap())
-            .collect();
+        let mut new_splits = Vec::default();
+        for split in splits.drain(..) {
+            new_splits.append(&mut handle_split(split, &mut transformer)?);
+        }
+        splits = new_splits;
     }
 
-    for module_member_offset in module_members {
-        let line = get_line_from_offset(lines, module_member_offset);
-        // We can safely convert to usize, as the result should always be positive
-        // this assumes module members are single-line!!
-        let extra_parens = count_parens(line) as usize;
-        transformer.writeln(
-            line[..line.len() - extra_parens].trim(),
-            MODULE_MEMBER_INDENT,
-        );
+    for module_member in module_members {
+        let module_member_offset = match module_member {
+            ModuleMember::Line(offset) => offset,
+            ModuleMember::FuncrefTable => {
+                transformer.emit_funcref_table();
+                continue;
+            }
+        };
+        emit_module_member(lines, module_member_offset, &mut transformer);
     }
 
     transformer.emit_end_module();
-    Ok(())
+    let state_usage = transformer.max_state_usage();
+    let unsplit_accesses = transformer.unsplit_accesses;
+    let declared_accesses = std::mem::take(&mut transformer.declared_accesses);
+    let state_instructions = std::mem::take(&mut transformer.state_instructions);
+    let table_base = transformer.table_base();
+    let mut source_map = std::mem::take(&mut transformer.source_map);
+    for mapping in &mut source_map.microtransactions {
+        if let Some(i) = transformer
+            .utx_function_names
+            .iter()
+            .position(|(_, name)| *name == mapping.name)
+        {
+            mapping.table_index = table_base + i;
+        }
+    }
+    let mut microtransactions = source_map
+        .microtransactions
+        .iter()
+        .map(|mapping| Microtransaction {
+            name: mapping.name.clone(),
+            table_index: mapping.table_index,
+            function: mapping.function.clone(),
+            saved_state: transformer.saved_state.get(&mapping.name).copied().unwrap_or(0),
+            successors: transformer
+                .successors
+                .get(&mapping.name)
+                .into_iter()
+                .flatten()
+                .filter_map(|name| {
+                    source_map
+                        .microtransactions
+                        .iter()
+                        .find(|successor| successor.name == *name)
+                        .map(|successor| successor.table_index)
+                })
+                .collect(),
+        })
+        .collect::<Vec<Microtransaction>>();
+    microtransactions.sort_by_key(|microtransaction| microtransaction.table_index);
+    let mut split_counts = BTreeMap::default();
+    for microtransaction in &microtransactions {
+        // The first microtransaction of every function is not a split
+        split_counts
+            .entry(microtransaction.function.clone())
+            .and_modify(|count| *count += 1)
+            .or_insert(0);
+    }
+
+    validate_wat(&output)?;
+    Ok(TransformOutput {
+        module: output,
+        microtransactions,
+        split_counts,
+        state_usage,
+        unsplit_accesses,
+        declared_accesses,
+        state_instructions,
+        source_map,
+        warnings,
+    })
+}
+
+/// Copy the module field starting at `offset` in the input to the transformed module
+fn emit_module_member(lines: &[&str], offset: usize, transformer: &mut WatEmitter) {
+    let line = get_line_from_offset(lines, offset);
+    // We can safely convert to usize, as the result should always be positive
+    // this assumes module members are single-line!!
+    let extra_parens = count_parens(line) as usize;
+    transformer.writeln(
+        line[..line.len() - extra_parens].trim(),
+        MODULE_MEMBER_INDENT,
+    );
 }
 
-fn extract_function<'a>(func: &'a Func, lines: &'a [&str]) -> Result<Function<'a>> {
-    Function::new(func, lines)
+/// A module field carried over to the transformed module
+enum ModuleMember {
+    /// The field at this offset in the input, copied as it is
+    Line(usize),
+    /// The first table, grown to hold the microtransactions
+    FuncrefTable,
+}
+
+fn extract_function<'a>(
+    func: &'a Func,
+    index: usize,
+    lines: &'a [&str],
+    context: &ModuleContext,
+) -> Result<Function<'a>> {
+    Function::new(func, index, lines, context)
 }
 
 fn handle_top_level_func<'a>(
     func: &'a Function,
+    selected: bool,
     transformer: &mut WatEmitter,
 ) -> Result<Vec<Split<'a>>> {
-    if func.ignore() {
+    if func.ignore() || !selected {
         transformer.emit_function(func);
         return Ok(Vec::default());
     }
@@ -85,8 +271,10 @@ fn handle_top_level_func<'a>(
         &func.instructions,
         &func.local_types,
         transformer,
-    );
+    )?;
+    transformer.emit_param_shadows(&func.param_shadows);
     transformer.utx_function_names.push((0, func.name.clone()));
+    transformer.source_map.add(&func.name, &func.name, None);
     handle_instructions(
         &func.name,
         &func.instructions,
@@ -101,9 +289,10 @@ pub fn setup_func(
     instructions: &[Instruction],
     locals: &[DataType],
     transformer: &mut WatEmitter,
-) {
+) -> Result<()> {
+    transformer.declared.clear();
     transformer.emit_utx_func_signature(name);
-    transformer.emit_locals(instructions, locals);
+    transformer.emit_locals(instructions, locals)
 }
 
 pub fn handle_instructions<'a>(
@@ -116,16 +305,45 @@ pub fn handle_instructions<'a>(
     let deferred_splits: Vec<Split> = Vec::default();
     for (i, instruction) in instructions.iter().enumerate() {
         transformer.current_scope_level = instruction.scopes.len();
-        let ty = InstructionType::from(instruction);
+        transformer.source_map.record_instruction(name, instruction.position);
+        let ty = InstructionType::try_from(instruction)?;
         match ty {
+            // Declared by the split before, along with the access the split was made at
+            InstructionType::Memory(_) if transformer.declared.contains(&instruction.position) => {}
             InstructionType::Memory(ty) => {
-                if ty.needs_split(&instruction.stack, transformer.skip_safe_splits)? {
+                match decide_split(&split_site(name, instruction, ty), instruction.span, transformer)? {
+                    SplitDecision::NoSplit => transformer.unsplit_accesses += 1,
+                    SplitDecision::Split | SplitDecision::BatchWithNext => {
+                        return setup_split(
+                            name,
+                            split_count + deferred_splits.len(),
+                            &instructions[i + 1..],
+                            locals,
+                            (instruction, SplitCulprit::Memory(ty), instruction.index),
+                            transformer,
+                        );
+                    }
+                }
+            }
+            InstructionType::Global(_) => {
+                let (global_index, global) = global_index(instruction.instr)
+                    .and_then(|index| resolve_global(&transformer.context.globals, index))
+                    .ok_or_else(|| ChopError::undefined_reference("global").at(instruction.span))?;
+                if transformer.split_globals
+                    && global.mutable
+                    && !global.private
+                    && !is_nosplit(&instruction.raw_text)
+                {
+                    let culprit = SplitCulprit::Global {
+                        address: synthetic_address(global_index).map_err(|err| err.at(instruction.span))?,
+                        instruction: instruction.raw_text.clone(),
+                    };
                     return setup_split(
                         name,
                         split_count + deferred_splits.len(),
                         &instructions[i + 1..],
                         locals,
-                        (instruction, ty, instruction.index),
+                        (instruction, culprit, instruction.index),
                         transformer,
                     );
                 }
@@ -133,27 +351,29 @@ pub fn handle_instructions<'a>(
             InstructionType::Benign(ty) => {
                 match ty {
                     BenignInstructionType::Block(ty) => match ty {
-                        BlockInstructionType::Block(id) => {
+                        BlockInstructionType::Block(_) => {
                             // Handle the special case of blocks being emitted on the previous indent
                             transformer.current_scope_level -= 1;
+                            let scope = instruction
+                                .scopes
+                                .last()
+                                .ok_or_else(|| {
+                                    ChopError::malformed_stack("Block instruction without a scope")
+                                        .at(instruction.span)
+                                })?;
                             let prev_stack_start = instruction
                                 .scopes
                                 .len()
                                 .checked_sub(2)
                                 .map(|i| instruction.scopes[i].stack_start)
                                 .unwrap_or(0);
-                            transformer.emit_save_stack_and_locals(
+                            transformer.emit_save_enclosing_stack_and_locals(
                                 &instruction.stack,
                                 prev_stack_start,
-                                true,
+                                scope.stack_start,
                                 locals,
                             );
-                            let block_instruction = if let Some(id) = id {
-                                format!("(block ${id}")
-                            } else {
-                                "(block".into()
-                            };
-                            transformer.emit_instruction(&block_instruction, None);
+                            transformer.emit_instruction(&scope.opening_text(true), None);
                             continue;
                         }
                         BlockInstructionType::End => {
@@ -161,17 +381,44 @@ pub fn handle_instructions<'a>(
                             continue;
                         }
                     },
-                    BenignInstructionType::Return => {
-                        if instruction.stack.is_empty() {
-                            transformer.emit_instruction("i32.const 0", Some("Return NULL".into()));
-                        }
+                    BenignInstructionType::Return if instruction.stack.is_empty() => {
+                        transformer.emit_instruction("i32.const 0", Some("Return NULL".into()));
                     }
                     _ => {}
                 }
             }
         }
-        transformer.emit_instruction(&instruction.raw_text, None);
+        transformer.emit_instruction(&instruction.raw_text, transformer.source_annotation(instruction));
     }
     transformer.emit_end_func();
     Ok(deferred_splits)
 }
+
+/// Decide what to do at a memory access, annotations taking precedence over the policy
+pub fn split_site<'a>(function: &'a str, instruction: &'a Instruction, access: MemoryInstructionType) -> SplitSite<'a> {
+    SplitSite {
+        function,
+        instruction: instruction.instr,
+        text: &instruction.raw_text,
+        access,
+        stack: &instruction.stack,
+        scopes: &instruction.scopes,
+    }
+}
+
+pub fn decide_split(site: &SplitSite, span: Span, transformer: &WatEmitter) -> Result<SplitDecision> {
+    match annotated_decision(site, &transformer.annotations) {
+        Some(decision) => Ok(decision),
+        None => transformer.policy.decide(site).map_err(|err| err.at(span)),
+    }
+}
+
+/// Accesses annotated with `@chop:nosplit`, or known to stay inside of private memory, are not split whatever the policy
+fn annotated_decision(site: &SplitSite, annotations: &Annotations) -> Option<SplitDecision> {
+    let in_private_region =
+        matches!(site.address_range(), Ok(Some(addresses)) if annotations.is_private(&addresses));
+    let is_private = in_private_region
+        || (site.address().is_ok_and(|address| address.private)
+            && site.access.offset() <= PRIVATE_OFFSET_LIMIT as u64);
+    (is_nosplit(site.text) || is_private).then_some(SplitDecision::NoSplit)
+}
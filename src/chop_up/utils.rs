@@ -1,6 +1,8 @@
 use crate::chop_up::instruction::DataType;
 
 pub const UTX_LOCALS: [DataType; 3] = [DataType::I32, DataType::I32, DataType::I32];
+/// Names given to the parameters of transformed functions
+pub const UTX_PARAM_NAMES: [&str; 3] = ["tx", "utx", "state"];
 pub const ADDRESS_LOCAL_NAME: &str = "memory_address";
 pub const STACK_JUGGLER_NAME: &str = "local";
 pub const MODULE_MEMBER_INDENT: usize = 1;
@@ -28,6 +30,19 @@ pub fn get_line_index_from_offset<'a>(lines: &'a [&'a str], offset: usize) -> us
     unreachable!()
 }
 
+/// Offset of the first character of the line at `index`
+pub fn get_offset_from_line_index(lines: &[&str], index: usize) -> usize {
+    lines[..index].iter().map(|l| l.len() + 1).sum()
+}
+
 pub fn get_line_from_offset<'a>(lines: &'a [&'a str], offset: usize) -> &'a str {
     lines[get_line_index_from_offset(lines, offset)]
 }
+
+/// A memory instruction with a static offset, leaving out the offset if it is 0
+pub fn with_offset(instruction: &str, offset: u32) -> String {
+    match offset {
+        0 => instruction.into(),
+        offset => format!("{instruction} offset={offset}"),
+    }
+}
@@ -0,0 +1,150 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use wasmparser::{Parser, Payload, Validator};
+use wast::core::{
+    ElemKind, ElemPayload, FuncKind, Instruction, ItemKind, ModuleField, ModuleKind, TableKind,
+};
+use wast::parser::{parse, ParseBuffer};
+use wast::token::Index;
+use wast::Wat;
+
+/// A transformed module that is not valid WebAssembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// The function the error was found in, if it could be attributed to one
+    pub function: Option<String>,
+    pub message: String,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.function {
+            Some(function) => write!(f, "Transformed function ${function} is invalid: {}", self.message),
+            None => write!(f, "Transformed module is invalid: {}", self.message),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// Re-parse and validate an emitted module.
+///
+/// Besides the checks of the WebAssembly validator - types and stack balance among them -
+/// active element segments are checked to fit within the bounds of their table.
+pub fn validate_wat(wat: &str) -> Result<(), ValidationError> {
+    let text_error = |err: wast::Error| ValidationError {
+        function: enclosing_function(wat, err.span().offset()),
+        message: err.message(),
+    };
+    let buffer = ParseBuffer::new(wat).map_err(text_error)?;
+    let mut module = parse::<Wat>(&buffer).map_err(text_error)?;
+    let fields = match &module {
+        Wat::Module(wast::core::Module { kind: ModuleKind::Text(fields), .. }) => fields,
+        _ => return Err(module_error("Expected a textual module")),
+    };
+    check_table_bounds(fields)?;
+    let defined_functions = fields
+        .iter()
+        .filter_map(|field| match field {
+            ModuleField::Func(func) if matches!(func.kind, FuncKind::Inline { .. }) => {
+                Some(func.id.map(|id| id.name().to_string()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<Option<String>>>();
+
+    let binary = module.encode().map_err(text_error)?;
+    Validator::new().validate_all(&binary).map(|_| ()).map_err(|err| ValidationError {
+        function: function_at_offset(&binary, err.offset())
+            .and_then(|i| defined_functions.get(i).cloned().flatten()),
+        message: err.message().to_string(),
+    })
+}
+
+fn module_error(message: &str) -> ValidationError {
+    ValidationError {
+        function: None,
+        message: message.into(),
+    }
+}
+
+fn check_table_bounds(fields: &[ModuleField]) -> Result<(), ValidationError> {
+    let mut tables = Vec::default();
+    for field in fields {
+        match field {
+            ModuleField::Import(import) => {
+                if let ItemKind::Table(ty) = &import.item.kind {
+                    tables.push((import.item.id.map(|id| id.name()), ty.limits.min));
+                }
+            }
+            ModuleField::Table(table) => match &table.kind {
+                TableKind::Normal { ty, .. } | TableKind::Import { ty, .. } => {
+                    tables.push((table.id.map(|id| id.name()), ty.limits.min))
+                }
+                TableKind::Inline { .. } => {}
+            },
+            _ => {}
+        }
+    }
+    for field in fields {
+        let ModuleField::Elem(elem) = field else { continue };
+        let (ElemKind::Active { table, offset }, ElemPayload::Indices(functions)) =
+            (&elem.kind, &elem.payload)
+        else {
+            continue;
+        };
+        let Some(Instruction::I32Const(offset)) = offset.instrs.first() else { continue };
+        let size = match table {
+            Index::Num(i, _) => tables.get(*i as usize).map(|(_, size)| *size),
+            Index::Id(id) => tables
+                .iter()
+                .find(|(name, _)| *name == Some(id.name()))
+                .map(|(_, size)| *size),
+        }
+        .ok_or(module_error("Element segment references an undefined table"))?;
+        let end = *offset as u32 as u64 + functions.len() as u64;
+        if end > size.into() {
+            let first_outside = size.saturating_sub(*offset as u32) as usize;
+            return Err(ValidationError {
+                function: functions.get(first_outside).map(|index| match index {
+                    Index::Id(id) => id.name().to_string(),
+                    Index::Num(i, _) => i.to_string(),
+                }),
+                message: format!("element segment ends at {end}, outside of the table of size {size}"),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Position among the defined functions of the function body containing `offset`
+fn function_at_offset(binary: &[u8], offset: usize) -> Option<usize> {
+    Parser::new(0)
+        .parse_all(binary)
+        .filter_map(|payload| match payload {
+            Ok(Payload::CodeSectionEntry(body)) => Some(body.range()),
+            _ => None,
+        })
+        .position(|range| range.contains(&offset))
+}
+
+/// Name of the function whose definition encloses `offset` in the text
+fn enclosing_function(wat: &str, offset: usize) -> Option<String> {
+    let start = wat.get(..offset)?.rfind("(func $")?;
+    let mut depth = 0;
+    for c in wat[start..offset].chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return None;
+        }
+    }
+    let name = &wat[start + "(func $".len()..];
+    name.split(|c: char| c.is_whitespace() || c == ')')
+        .next()
+        .map(String::from)
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use wast::core::Instruction::{
+    self as WastInstruction, I32Add, I32And, I32Eq, I32Eqz, I32GtS, I32GtU, I32Load16u,
+    I32Load8u, I32LtS, I32LtU, I32Mul, I32Ne, I32Shl, I32Sub, I32WrapI64, I64Add, I64And, I64Eq,
+    I64ExtendI32U, I64GtS, I64GtU, I64Load16u, I64Load32u, I64Load8u, I64LtS, I64LtU, I64Mul,
+    I64Ne, I64Sub, LocalGet, LocalSet, LocalTee,
+};
+
+use crate::chop_up::instruction::DataType;
+use crate::chop_up::instruction_stream::{LocalIndexSpace, StackValue};
+
+const I32_WINDOW: i64 = 1 << 32;
+/// Largest offset, either way, from an address derived from a private global that is taken to stay private,
+/// as large as any stack frame is expected to be
+pub const PRIVATE_OFFSET_LIMIT: i64 = 1 << 16;
+
+/// Inclusive bounds on the values an expression may evaluate to.
+///
+/// 32 bit values are kept in any representation equal to them modulo 2^32,
+/// so that wrapping arithmetic stays exact as long as it does not overflow 64 bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: i64,
+    pub max: i64,
+}
+
+impl Bounds {
+    pub fn constant(value: i64) -> Self {
+        Self { min: value, max: value }
+    }
+
+    fn new(min: i64, max: i64) -> Self {
+        Self { min, max }
+    }
+
+    /// The value, if the bounds only allow one
+    pub fn as_constant(&self) -> Option<i64> {
+        (self.min == self.max).then_some(self.min)
+    }
+
+    /// The values as unsigned, if they do not wrap around
+    pub fn unsigned(&self, ty: DataType) -> Option<Range<u64>> {
+        let (min, max) = match ty {
+            DataType::I32 => {
+                let window = self.min.div_euclid(I32_WINDOW);
+                if self.max.div_euclid(I32_WINDOW) != window {
+                    return None;
+                }
+                (self.min - window * I32_WINDOW, self.max - window * I32_WINDOW)
+            }
+            DataType::I64 if self.min >= 0 => (self.min, self.max),
+            _ => return None,
+        };
+        Some(min as u64..max as u64 + 1)
+    }
+
+    fn add(self, other: Self) -> Option<Self> {
+        Some(Self::new(self.min.checked_add(other.min)?, self.max.checked_add(other.max)?))
+    }
+
+    fn sub(self, other: Self) -> Option<Self> {
+        Some(Self::new(self.min.checked_sub(other.max)?, self.max.checked_sub(other.min)?))
+    }
+
+    fn mul(self, other: Self) -> Option<Self> {
+        let products = [
+            self.min.checked_mul(other.min)?,
+            self.min.checked_mul(other.max)?,
+            self.max.checked_mul(other.min)?,
+            self.max.checked_mul(other.max)?,
+        ];
+        Some(Self::new(*products.iter().min()?, *products.iter().max()?))
+    }
+}
+
+impl Display for Bounds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.as_constant() {
+            Some(value) => write!(f, "{value}"),
+            None => write!(f, "{}..={}", self.min, self.max),
+        }
+    }
+}
+
+/// What is known about the locals of a function at the current instruction
+#[derive(Default)]
+pub struct LocalValues {
+    values: HashMap<u32, StackValue>,
+    /// Locals assigned inside of each enclosing block, the innermost last
+    assigned: Vec<HashSet<u32>>,
+}
+
+impl LocalValues {
+    pub fn enter_block(&mut self) {
+        self.assigned.push(HashSet::default());
+    }
+
+    /// Forget the locals assigned inside of the block,
+    /// as branches out of it may have skipped any of the assignments
+    pub fn exit_block(&mut self) {
+        let Some(assigned) = self.assigned.pop() else {
+            return;
+        };
+        for index in &assigned {
+            self.values.remove(index);
+        }
+        if let Some(enclosing) = self.assigned.last_mut() {
+            enclosing.extend(assigned);
+        }
+    }
+
+    /// Refine the values on the stack after `instruction` consumed `operands`, the top of the stack last.
+    /// Only instructions pushing a value change the top of the stack.
+    pub fn update(
+        &mut self,
+        instruction: &WastInstruction,
+        operands: &[StackValue],
+        stack: &mut [StackValue],
+        locals: &LocalIndexSpace,
+    ) {
+        match instruction {
+            LocalSet(index) | LocalTee(index) => {
+                let value = match instruction {
+                    LocalTee(_) => stack.last(),
+                    _ => operands.last(),
+                };
+                if let (Some(index), Some(value)) = (locals.resolve(index), value) {
+                    self.values.insert(index, *value);
+                    if let Some(assigned) = self.assigned.last_mut() {
+                        assigned.insert(index);
+                    }
+                }
+            }
+            LocalGet(index) => {
+                let known = locals.resolve(index).and_then(|index| self.values.get(&index));
+                if let (Some(known), Some(result)) = (known, stack.last_mut()) {
+                    result.bounds = known.bounds;
+                    result.private = known.private;
+                }
+            }
+            _ => {
+                if let Some(result) = stack.last_mut() {
+                    refine_result(instruction, operands, result);
+                }
+            }
+        }
+    }
+}
+
+/// Bounds of the value pushed by an instruction, and whether it is still derived from a private global
+fn refine_result(instruction: &WastInstruction, operands: &[StackValue], result: &mut StackValue) {
+    let bounds = |i: usize| operands.get(i).and_then(|value| value.bounds);
+    let is_offset = |i: usize| {
+        operands.get(i).is_some_and(|value| {
+            !value.private
+                && value
+                    .bounds
+                    .is_some_and(|bounds| bounds.min >= -PRIVATE_OFFSET_LIMIT && bounds.max <= PRIVATE_OFFSET_LIMIT)
+        })
+    };
+    let is_private = |i: usize| operands.get(i).is_some_and(|value| value.private);
+    match instruction {
+        I32Add | I64Add => {
+            result.bounds = bounds(0).zip(bounds(1)).and_then(|(a, b)| a.add(b));
+            // A small offset from a private address stays private
+            result.private = (is_private(0) && is_offset(1)) || (is_offset(0) && is_private(1));
+        }
+        I32Sub | I64Sub => {
+            result.bounds = bounds(0).zip(bounds(1)).and_then(|(a, b)| a.sub(b));
+            result.private = is_private(0) && is_offset(1);
+        }
+        I32Mul | I64Mul => result.bounds = bounds(0).zip(bounds(1)).and_then(|(a, b)| a.mul(b)),
+        I32And | I64And => {
+            // Masking with a non-negative constant bounds the result by the mask
+            let mask = [bounds(0), bounds(1)]
+                .into_iter()
+                .flatten()
+                .filter_map(|bounds| bounds.as_constant())
+                .filter(|&mask| mask >= 0 && (result.ty != DataType::I32 || mask <= i32::MAX as i64))
+                .min();
+            // Masking may move a private address anywhere, so the result is not private
+            result.bounds = mask.map(|mask| Bounds::new(0, mask));
+        }
+        I32Shl => {
+            let shift = bounds(1).and_then(|bounds| bounds.as_constant()).map(|shift| shift & 31);
+            result.bounds = bounds(0)
+                .zip(shift)
+                .and_then(|(value, shift)| value.mul(Bounds::constant(1 << shift)));
+        }
+        I32Load8u(_) | I64Load8u(_) => result.bounds = Some(Bounds::new(0, u8::MAX as i64)),
+        I32Load16u(_) | I64Load16u(_) => result.bounds = Some(Bounds::new(0, u16::MAX as i64)),
+        I64Load32u(_) => result.bounds = Some(Bounds::new(0, u32::MAX as i64)),
+        I32Eqz | I32Eq | I32Ne | I32GtS | I32GtU | I32LtS | I32LtU | I64Eq | I64Ne | I64GtS
+        | I64GtU | I64LtS | I64LtU => result.bounds = Some(Bounds::new(0, 1)),
+        I64ExtendI32U => {
+            result.bounds = Some(
+                bounds(0)
+                    .and_then(|bounds| bounds.unsigned(DataType::I32))
+                    .map(|range| Bounds::new(range.start as i64, range.end as i64 - 1))
+                    .unwrap_or(Bounds::new(0, u32::MAX as i64)),
+            );
+        }
+        // Equal modulo 2^32
+        I32WrapI64 => result.bounds = bounds(0),
+        _ => {}
+    }
+}
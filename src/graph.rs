@@ -0,0 +1,215 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use anyhow::Result;
+use wast::core::Instruction as WastInstruction;
+use wast::core::{ItemKind, ModuleField};
+use wast::parser::{parse, ParseBuffer};
+use wast::token::Index;
+use wast::Wat;
+
+use crate::chop_up::{
+    instruction_name, transform_wat, ChopError, Function, ModuleContext, SplitOptions, TransformOutput,
+};
+use crate::extract_module_fields;
+
+/// Write the control-flow graph of every transaction function of a module in Graphviz DOT.
+///
+/// Every function is drawn as a cluster of basic blocks, with the blocks starting at a split access highlighted.
+/// The microtransactions the function is split into are drawn next to it,
+/// each pointing at the block it starts in and at the microtransactions it may continue with.
+pub fn write_graph(input: &str, options: &SplitOptions, output: &mut dyn Write) -> Result<()> {
+    let buffer = ParseBuffer::new(input)?;
+    let wat = parse::<Wat>(&buffer)?;
+    let lines = input.split('\n').collect::<Vec<&str>>();
+    let transformed = transform_wat(&wat, &lines, options)?;
+    let fields = extract_module_fields(&wat)?;
+    let context = ModuleContext::from_module_fields(fields)?;
+
+    writeln!(output, "digraph {{")?;
+    writeln!(output, "    node [shape=box fontname=\"monospace\"];")?;
+    let mut function_index = 0;
+    for field in fields {
+        match field {
+            ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Func(_)) => function_index += 1,
+            ModuleField::Func(func) => {
+                let function = Function::new(func, function_index, &lines, &context)?;
+                function_index += 1;
+                if transformed.split_counts.contains_key(&function.name) {
+                    write_function_graph(&function, &transformed, output)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    writeln!(output, "}}")?;
+    Ok(())
+}
+
+fn write_function_graph(function: &Function, transformed: &TransformOutput, output: &mut dyn Write) -> Result<()> {
+    let name = &function.name;
+    let instructions = &function.instructions;
+    // Where every microtransaction of the function starts, the entry at the first instruction
+    let starts = transformed
+        .source_map
+        .microtransactions
+        .iter()
+        .filter(|mapping| mapping.function == *name)
+        .map(|mapping| {
+            let start = match mapping.culprit {
+                Some(_) => mapping.instructions.first().map(|range| range.start).unwrap_or(0),
+                None => 0,
+            };
+            (mapping.name.as_str(), start)
+        })
+        .collect::<Vec<(&str, usize)>>();
+    let split_accesses = starts
+        .iter()
+        .filter(|(microtransaction, _)| microtransaction != name)
+        .map(|&(_, start)| start)
+        .collect::<BTreeSet<usize>>();
+    let targets = branch_targets(instructions.iter().map(|instruction| instruction.instr))?;
+
+    // Basic blocks start at the function entry, at the end of blocks branched to,
+    // after every branch, and at every split access
+    let mut leaders = BTreeSet::from([0]);
+    for (position, instruction) in instructions.iter().enumerate() {
+        match instruction.instr {
+            WastInstruction::End(_) => {
+                leaders.insert(position);
+            }
+            WastInstruction::Br(_) | WastInstruction::BrIf(_) | WastInstruction::Return => {
+                leaders.insert(position + 1);
+            }
+            _ => {}
+        }
+    }
+    leaders.extend(&split_accesses);
+    leaders.retain(|&leader| leader < instructions.len());
+    let leaders = leaders.into_iter().collect::<Vec<usize>>();
+
+    let node = |position: Option<usize>| match position {
+        Some(position) => format!("\"{name}@{position}\""),
+        None => format!("\"{name}@exit\""),
+    };
+    writeln!(output, "    subgraph \"cluster_{name}\" {{")?;
+    writeln!(output, "        label=\"${name}\";")?;
+    for (i, &leader) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied().unwrap_or(instructions.len());
+        let label = instructions[leader..end]
+            .iter()
+            .map(|instruction| format!("{}\\l", escape(&instruction.raw_text)))
+            .collect::<String>();
+        let style = if split_accesses.contains(&leader) { " style=filled fillcolor=\"#f4cccc\"" } else { "" };
+        writeln!(output, "        {} [label=\"{label}\"{style}];", node(Some(leader)))?;
+    }
+    writeln!(output, "        {} [label=\"exit\" shape=oval];", node(None))?;
+
+    for (i, &leader) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied();
+        let last = end.unwrap_or(instructions.len()) - 1;
+        let successors = match &targets[last] {
+            Branch::Always(target) => vec![*target],
+            Branch::Conditional(target) => vec![*target, end],
+            Branch::None => vec![end],
+        };
+        for successor in successors {
+            writeln!(output, "        {} -> {};", node(Some(leader)), node(successor))?;
+        }
+    }
+
+    for (microtransaction, start) in &starts {
+        writeln!(output, "        \"utx {microtransaction}\" [label=\"${microtransaction}\" shape=ellipse];")?;
+        writeln!(output, "        \"utx {microtransaction}\" -> {} [style=dashed];", node(Some(*start)))?;
+    }
+    for microtransaction in transformed.microtransactions.iter().filter(|microtransaction| microtransaction.function == *name) {
+        for successor in transformed
+            .microtransactions
+            .iter()
+            .filter(|successor| microtransaction.successors.contains(&successor.table_index))
+        {
+            writeln!(output, "        \"utx {}\" -> \"utx {}\" [style=bold];", microtransaction.name, successor.name)?;
+        }
+    }
+    writeln!(output, "    }}")?;
+    Ok(())
+}
+
+/// Where control goes after an instruction, `None` standing for leaving the function
+enum Branch {
+    /// Falls through to the next instruction
+    None,
+    Always(Option<usize>),
+    /// Either branches or falls through
+    Conditional(Option<usize>),
+}
+
+/// The branch taken by every instruction.
+/// Branching to a block continues at its `end`, branching past the outermost block leaves the function.
+/// Labels resolve to the innermost enclosing block of that name.
+/// Other control instructions are rejected, as their branches are not modelled.
+fn branch_targets<'a>(instructions: impl Iterator<Item = &'a WastInstruction<'a>>) -> Result<Vec<Branch>> {
+    let instructions = instructions.collect::<Vec<_>>();
+    // Position of the `end` of every block, by the position of the block
+    let mut ends = BTreeMap::default();
+    let mut open = Vec::default();
+    for (position, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            WastInstruction::Block(_) => open.push(position),
+            WastInstruction::End(_) => {
+                if let Some(block) = open.pop() {
+                    ends.insert(block, position);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut scopes: Vec<(usize, Option<&str>)> = Vec::default();
+    let mut targets = Vec::default();
+    for (position, instruction) in instructions.iter().enumerate() {
+        let target = |label: &Index| {
+            let depth = match label {
+                Index::Num(depth, _) => *depth as usize,
+                Index::Id(id) => scopes.iter().rev().position(|(_, name)| *name == Some(id.name()))?,
+            };
+            let block = scopes.len().checked_sub(depth + 1)?;
+            ends.get(&scopes[block].0).copied()
+        };
+        targets.push(match instruction {
+            WastInstruction::Br(label) => Branch::Always(target(label)),
+            WastInstruction::BrIf(label) => Branch::Conditional(target(label)),
+            WastInstruction::Return => Branch::Always(None),
+            WastInstruction::Loop(_)
+            | WastInstruction::If(_)
+            | WastInstruction::Else(_)
+            | WastInstruction::BrTable(_)
+            | WastInstruction::BrOnNull(_)
+            | WastInstruction::BrOnNonNull(_)
+            | WastInstruction::Try(_)
+            | WastInstruction::Catch(_)
+            | WastInstruction::CatchAll
+            | WastInstruction::Delegate(_)
+            | WastInstruction::Throw(_)
+            | WastInstruction::Rethrow(_)
+            | WastInstruction::ReturnCall(_)
+            | WastInstruction::ReturnCallIndirect(_) => {
+                return Err(ChopError::unsupported_instruction(instruction_name(instruction)).into());
+            }
+            _ => Branch::None,
+        });
+        match instruction {
+            WastInstruction::Block(block) => scopes.push((position, block.label.map(|id| id.name()))),
+            WastInstruction::End(_) => {
+                scopes.pop();
+            }
+            _ => {}
+        }
+    }
+    Ok(targets)
+}
+
+/// Escape text for a quoted DOT string
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
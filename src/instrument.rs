@@ -0,0 +1,253 @@
+use anyhow::{anyhow, Result};
+use wast::core::{
+    ElemPayload, Export, ExportKind, FuncKind, FunctionType, GlobalKind, Import, Instruction, ItemKind, ItemSig,
+    Local, MemoryKind, MemoryType, ModuleField, ModuleKind, TypeUse, ValType,
+};
+use wast::parser::{parse, ParseBuffer};
+use wast::token::{Index, Span};
+use wast::Wat;
+
+use crate::chop_up::{
+    resolve_global, synthetic_address, DataType, InstructionType, MemoryInstructionType, ModuleContext, IGNORE_FUNC_PREFIX,
+};
+use crate::extract_module_fields;
+
+/// Host function instrumented modules report their accesses to, taking the address and the index of the site
+pub const RECORD_MODULE: &str = "chop_up";
+pub const RECORD_FUNCTION: &str = "record";
+/// Export names added to instrumented modules so the transaction can be driven from the outside
+pub const ENTRY_EXPORT: &str = "__chop_up_verify";
+const TABLE_EXPORT: &str = "__chop_up_verify_table";
+const MEMORY_EXPORT: &str = "__chop_up_verify_memory";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Load,
+    Store,
+    Global,
+}
+
+/// An instruction reporting the accesses it makes
+#[derive(Clone, Debug)]
+pub struct Site {
+    pub kind: AccessKind,
+    /// Name of the function containing the instruction
+    pub function: String,
+    /// Position of the instruction in the body of the function as written
+    pub position: usize,
+    /// Added to the address reported, as the instruction reports it before applying its offset
+    pub offset: u64,
+}
+
+pub struct Instrumented {
+    /// The instrumented module in the binary format
+    pub binary: Vec<u8>,
+    /// Every instrumented instruction, by the index it reports
+    pub sites: Vec<Site>,
+}
+
+/// Rewrite a module to report every memory access made by functions not ignored by the transformation.
+/// If `globals` is set accesses to mutable globals are reported as well,
+/// at the synthetic address the transformation yields for them.
+///
+/// `entry` is exported as [`ENTRY_EXPORT`], along with the table and memory of the module if it has them.
+pub fn instrument(input: &str, entry: &str, globals: bool) -> Result<Instrumented> {
+    let buffer = ParseBuffer::new(input)?;
+    let mut wat = parse::<Wat>(&buffer)?;
+    let context = ModuleContext::from_module_fields(extract_module_fields(&wat)?)?;
+    let entry_index = (0..context.functions.len())
+        .find(|&index| context.function_name(index) == entry)
+        .ok_or(anyhow!("No function named {entry}"))? as u32;
+    let Wat::Module(module) = &mut wat else {
+        return Err(anyhow!("Components are not supported"));
+    };
+    let span = module.span;
+    let ModuleKind::Text(fields) = &mut module.kind else {
+        return Err(anyhow!("Binary modules are not supported"));
+    };
+
+    let address_type = if fields.iter().any(is_memory64) { ValType::I64 } else { ValType::I32 };
+    let mut global_addresses = Vec::default();
+    for (index, global) in context.globals.iter().enumerate() {
+        let address = (globals && global.mutable).then(|| synthetic_address(index as u32)).transpose()?;
+        global_addresses.push(address);
+    }
+    // The synthetic address of the global accessed, if it is reported
+    let global_address = |instruction: &Instruction| match instruction {
+        Instruction::GlobalGet(index) | Instruction::GlobalSet(index) => resolve_global(&context.globals, index)
+            .and_then(|(index, _)| global_addresses.get(index as usize).copied().flatten()),
+        _ => None,
+    };
+
+    let mut sites = Vec::default();
+    let mut function_index = 0;
+    for field in fields.iter_mut() {
+        match field {
+            ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Func(_)) => function_index += 1,
+            ModuleField::Func(func) => {
+                let name = context.function_name(function_index);
+                function_index += 1;
+                let params = context.resolve_type_use(&func.ty)?.params.len();
+                let FuncKind::Inline { locals, expression } = &mut func.kind else {
+                    continue;
+                };
+                let instrumented = !name.starts_with(IGNORE_FUNC_PREFIX);
+                let scratch = Scratch {
+                    address: (params + locals.len()) as u32,
+                    address_type,
+                    span,
+                };
+                if instrumented {
+                    let mut extended = std::mem::take(locals).into_vec();
+                    extended.extend([address_type, ValType::I32, ValType::I64, ValType::F32, ValType::F64].map(|ty| {
+                        Local { id: None, name: None, ty }
+                    }));
+                    *locals = extended.into_boxed_slice();
+                }
+                let mut instrs = Vec::with_capacity(expression.instrs.len());
+                for (position, mut instruction) in std::mem::take(&mut expression.instrs).into_vec().into_iter().enumerate() {
+                    shift_function_index(&mut instruction);
+                    if instrumented {
+                        let site = sites.len() as i32;
+                        // Other memory instructions are rejected by the transformation
+                        match InstructionType::try_from(&instruction) {
+                            Ok(InstructionType::Memory(access)) => {
+                                let kind = match access {
+                                    MemoryInstructionType::Load { .. } => AccessKind::Load,
+                                    MemoryInstructionType::Store { .. } => AccessKind::Store,
+                                };
+                                sites.push(Site { kind, function: name.clone(), position, offset: access.offset() });
+                                instrs.extend(scratch.report_access(access, site));
+                            }
+                            Ok(InstructionType::Global(_)) => {
+                                if let Some(address) = global_address(&instruction) {
+                                    sites.push(Site { kind: AccessKind::Global, function: name.clone(), position, offset: 0 });
+                                    instrs.extend([
+                                        Instruction::I64Const(address as i64),
+                                        Instruction::I32Const(site),
+                                        Instruction::Call(Index::Num(0, span)),
+                                    ]);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    instrs.push(instruction);
+                }
+                expression.instrs = instrs.into_boxed_slice();
+            }
+            ModuleField::Export(export) if matches!(export.kind, ExportKind::Func) => shift_index(&mut export.item),
+            ModuleField::Start(index) => shift_index(index),
+            ModuleField::Elem(elem) => match &mut elem.payload {
+                ElemPayload::Indices(indices) => indices.iter_mut().for_each(shift_index),
+                ElemPayload::Exprs { exprs, .. } => exprs
+                    .iter_mut()
+                    .flat_map(|expr| expr.instrs.iter_mut())
+                    .for_each(shift_function_index),
+            },
+            ModuleField::Global(global) => {
+                if let GlobalKind::Inline(expr) = &mut global.kind {
+                    expr.instrs.iter_mut().for_each(shift_function_index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let has_table = fields.iter().any(|field| matches!(field, ModuleField::Table(_)));
+    let has_memory = fields.iter().any(|field| {
+        matches!(field, ModuleField::Memory(_))
+            || matches!(field, ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Memory(_)))
+    });
+    let export = |name, kind, index| ModuleField::Export(Export { span, name, kind, item: Index::Num(index, span) });
+    fields.push(export(ENTRY_EXPORT, ExportKind::Func, entry_index + 1));
+    if has_table {
+        fields.push(export(TABLE_EXPORT, ExportKind::Table, 0));
+    }
+    if has_memory {
+        fields.push(export(MEMORY_EXPORT, ExportKind::Memory, 0));
+    }
+    // Imported functions come first in the index space, which is why every numeric function index is shifted
+    fields.insert(0, ModuleField::Import(Import {
+        span,
+        module: RECORD_MODULE,
+        field: RECORD_FUNCTION,
+        item: ItemSig {
+            span,
+            id: None,
+            name: None,
+            kind: ItemKind::Func(TypeUse {
+                index: None,
+                inline: Some(FunctionType {
+                    params: Box::new([(None, None, ValType::I64), (None, None, ValType::I32)]),
+                    results: Box::new([]),
+                }),
+            }),
+        },
+    }));
+
+    Ok(Instrumented { binary: wat.encode()?, sites })
+}
+
+/// Locals added to every instrumented function, following its own
+struct Scratch {
+    /// Local holding the address, followed by a local for a stored value of each type
+    address: u32,
+    address_type: ValType<'static>,
+    span: Span,
+}
+
+impl Scratch {
+    /// Report the address of an access before it is made, leaving the stack as it was
+    fn report_access(&self, access: MemoryInstructionType, site: i32) -> Vec<Instruction<'static>> {
+        let local = |index: u32| Index::Num(index, self.span);
+        let value = match access {
+            MemoryInstructionType::Load { .. } => None,
+            MemoryInstructionType::Store { ty, .. } => Some(local(
+                self.address
+                    + match ty {
+                        DataType::I32 => 1,
+                        DataType::I64 => 2,
+                        DataType::F32 => 3,
+                        DataType::F64 => 4,
+                    },
+            )),
+        };
+        let mut instructions = Vec::default();
+        instructions.extend(value.map(Instruction::LocalSet));
+        instructions.push(Instruction::LocalTee(local(self.address)));
+        if matches!(self.address_type, ValType::I32) {
+            instructions.push(Instruction::I64ExtendI32U);
+        }
+        instructions.extend([
+            Instruction::I32Const(site),
+            Instruction::Call(Index::Num(0, self.span)),
+            Instruction::LocalGet(local(self.address)),
+        ]);
+        instructions.extend(value.map(Instruction::LocalGet));
+        instructions
+    }
+}
+
+fn is_memory64(field: &ModuleField) -> bool {
+    match field {
+        ModuleField::Memory(memory) => match &memory.kind {
+            MemoryKind::Normal(ty) | MemoryKind::Import { ty, .. } => matches!(ty, MemoryType::B64 { .. }),
+            MemoryKind::Inline { is_32, .. } => !is_32,
+        },
+        ModuleField::Import(import) => matches!(import.item.kind, ItemKind::Memory(MemoryType::B64 { .. })),
+        _ => false,
+    }
+}
+
+fn shift_function_index(instruction: &mut Instruction) {
+    if let Instruction::Call(index) | Instruction::ReturnCall(index) | Instruction::RefFunc(index) = instruction {
+        shift_index(index);
+    }
+}
+
+fn shift_index(index: &mut Index) {
+    if let Index::Num(index, _) = index {
+        *index += 1;
+    }
+}
@@ -1,100 +1,154 @@
+use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::path::Path;
 
 use anyhow::{anyhow, Error, Result};
-use wast::core::{FuncKind, ModuleField, ModuleKind};
+use wast::core::{ModuleField, ModuleKind};
 use wast::parser::{parse, ParseBuffer};
 use wast::Wat;
 
-use crate::chop_up::{emit_transformed_wat, IGNORE_FUNC_PREFIX, InstructionType, MemoryInstructionType};
+use crate::chop_up::transform_wat;
+pub use crate::chop_up::MemoryInstructionType;
+pub use crate::chop_up::{
+    parse_address_range, AbiLayout, AlwaysSplit, Bounds, ChopError, Culprit, DataType, Diagnostic, ExplainLevel, FunctionFilter,
+    MemoryInstructionSubtype, Microtransaction, MicrotransactionMapping, ModuleFormat, Scope, ScopeType, ShadowStack, SkipSafe,
+    SourceMap, SplitDecision, SplitOptions, SplitPolicy, SplitSite, StackValue, StaticAnalysis, TransformOutput,
+    ValidationError,
+};
+pub use crate::analysis::{analyze_wat, write_reports, AnalysisReport, CsvRows, FunctionAnalysis, OutputFormat};
+pub use crate::graph::write_graph;
+pub use crate::runtime::{RecordedAccess, Runtime, Trace, Utx};
+pub use crate::stats::{split_stats, FunctionStats, SplitStats};
+pub use crate::verify::{verify_output, Divergence, Transaction, Verification};
 
+mod analysis;
 mod chop_up;
+mod graph;
+mod instrument;
+mod runtime;
+mod stats;
+mod verify;
 
-pub fn run_split(file_path: &str, state_size: usize, skip_safe: bool, explain: bool, output: &mut dyn Write) -> Result<()> {
+/// Split the module in `file_path` into `output`,
+/// along with the source map and statistics on how it grew if the options ask for them
+pub fn run_split(file_path: &str, options: &SplitOptions, output: &mut dyn Write) -> Result<()> {
     let file_contents = read_file(file_path)?;
-    transform_wat_string(&file_contents, output, state_size, skip_safe, explain)
+    let transformed = split_wat_string(&file_contents, options)
+        .map_err(|err| with_diagnostic(err.into(), file_path, &file_contents))?;
+    for warning in &transformed.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    output.write_all(&transformed.encode(options.format)?)?;
+    if let Some(source_map_path) = &options.source_map_path {
+        fs::write(source_map_path, serde_json::to_string_pretty(&transformed.source_map)?)
+            .map_err(|err| anyhow!("Failed to write source map: {err}"))?;
+    }
+    if options.stats {
+        split_stats(&file_contents, &transformed)?.write(&mut io::stderr())?;
+    }
+    Ok(())
+}
+
+/// Write the control-flow graph of every transaction function in `file_path` to `output` as Graphviz DOT
+pub fn run_graph(file_path: &str, options: &SplitOptions, output: &mut dyn Write) -> Result<()> {
+    let file_contents = read_file(file_path)?;
+    write_graph(&file_contents, options, output).map_err(|err| with_diagnostic(err, file_path, &file_contents))
 }
 
-pub fn transform_wat_string(input: &str, output: &mut dyn Write, state_size: usize, skip_safe: bool, explain: bool) -> Result<()> {
+pub fn transform_wat_string(input: &str, output: &mut dyn Write, options: &SplitOptions) -> Result<(), ChopError> {
+    source_map_wat_string(input, output, options)?;
+    Ok(())
+}
+
+/// Transform like [`transform_wat_string`], returning the map from microtransactions to the original code
+pub fn source_map_wat_string(input: &str, output: &mut dyn Write, options: &SplitOptions) -> Result<SourceMap, ChopError> {
+    let transformed = split_wat_string(input, options)?;
+    output.write_all(&transformed.encode(options.format)?)?;
+    Ok(transformed.source_map)
+}
+
+/// Split a module, returning the transformed module along with a description of its microtransactions
+pub fn split_wat_string(input: &str, options: &SplitOptions) -> Result<TransformOutput, ChopError> {
     let buffer = ParseBuffer::new(input)?;
     let wat = parse(&buffer)?;
-    emit_transformed_wat(
-        &wat,
-        &input.split('\n').collect::<Vec<&str>>(),
-        output,
-        skip_safe,
-        state_size,
-        explain,
-    )
+    transform_wat(&wat, &input.split('\n').collect::<Vec<&str>>(), options)
+}
+
+pub fn run_transaction(file_path: &str, args: &[i64]) -> Result<()> {
+    let file_contents = read_file(file_path)?;
+    let trace = trace_wat_string(&file_contents, args)?;
+    println!("{trace}");
+    Ok(())
 }
 
-pub enum OutputFormat {
-    Normal,
-    CSV,
+pub fn trace_wat_string(input: &str, args: &[i64]) -> Result<Trace> {
+    Runtime::from_wat(input)?.run_transaction(args)
 }
 
-pub fn run_analysis(file_path: &str, output_format: OutputFormat) -> Result<()> {
+pub fn run_verify(file_path: &str, transaction: &Transaction, options: &SplitOptions) -> Result<()> {
     let file_contents = read_file(file_path)?;
-    let buffer = ParseBuffer::new(&file_contents)?;
-    let wat = parse(&buffer)?;
-    let (instruction_count, load_count, store_count) = analyze_wat(&wat)?;
-
-    let memory_instruction_count = load_count + store_count;
-    let normal_instruction_count = instruction_count - memory_instruction_count;
-    match output_format {
-        OutputFormat::Normal => {
-            println!("\
-Analysis for file: {file_path}
-Total size = {file_size}
-Total instructions = {instruction_count}
-  Of which:
-  Normal instructions = {normal_instruction_count}
-  Memory instructions = {memory_instruction_count}
-    Of which:
-    Load instructions  = {load_count}
-    Store instructions = {store_count}",
-                     file_size = file_contents.bytes().len());
-        }
-        OutputFormat::CSV => {
-            println!("\
-file,size,total_instructions,normal_instructions,memory_instructions,load_instructions,store_instructions
-{file_path},{file_size},{instruction_count},{normal_instruction_count},{memory_instruction_count},{load_count},{store_count}",
-                     file_size = file_contents.bytes().len());
-        }
+    let verification = verify_wat_string(&file_contents, transaction, options)
+        .map_err(|err| with_diagnostic(err, file_path, &file_contents))?;
+    println!("{verification}");
+    if !verification.is_equivalent() {
+        return Err(anyhow!("Split transaction is not equivalent to the original"));
     }
     Ok(())
 }
 
-fn analyze_wat(wat: &Wat) -> Result<(i32, i32, i32)> {
-    let mut instruction_count = 0;
-    let mut load_count = 0;
-    let mut store_count = 0;
-    for field in extract_module_fields(wat)? {
-        if let ModuleField::Func(func) = field {
-            if let Some(name) = func.name {
-                if name.name.starts_with(IGNORE_FUNC_PREFIX) { continue; }
-            }
-
-            if let FuncKind::Inline { expression, .. } = &func.kind {
-                for instruction in expression.instrs.iter() {
-                    match InstructionType::from(instruction) {
-                        InstructionType::Memory(ty) => {
-                            instruction_count += 1;
-                            match ty {
-                                MemoryInstructionType::Load { .. } => load_count += 1,
-                                MemoryInstructionType::Store { .. } => store_count += 1
-                            }
-                        }
-                        InstructionType::Benign(_) => instruction_count += 1
-                    }
-                }
-            } else {
-                return Err(anyhow!("FuncKind is not inline"));
-            };
+pub fn verify_wat_string(input: &str, transaction: &Transaction, options: &SplitOptions) -> Result<Verification> {
+    verify::verify(input, transaction, options)
+}
+
+/// Analyze every file given, and every `.wat` file directly inside of every directory given.
+/// A single file is written as it is, anything else with [`write_reports`].
+pub fn run_analysis(
+    paths: &[&str],
+    output_format: OutputFormat,
+    rows: CsvRows,
+    total: bool,
+    output: &mut dyn Write,
+) -> Result<()> {
+    let mut file_paths = Vec::default();
+    for path in paths {
+        if Path::new(path).is_dir() {
+            let mut wat_files = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .filter(|path| path.is_file() && path.extension().is_some_and(|extension| extension == "wat"))
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<String>>();
+            wat_files.sort();
+            file_paths.append(&mut wat_files);
+        } else {
+            file_paths.push(path.to_string());
+        }
+    }
+    let reports = file_paths
+        .iter()
+        .map(|file_path| {
+            let file_contents = read_file(file_path)?;
+            let mut report = analyze_wat(&file_contents).map_err(|err| anyhow!("{file_path}: {err}"))?;
+            report.file = Some(file_path.clone());
+            Ok(report)
+        })
+        .collect::<Result<Vec<AnalysisReport>>>()?;
+    match reports.as_slice() {
+        [report] if paths.len() == 1 && !total && rows == CsvRows::File && Path::new(paths[0]).is_file() => {
+            report.write(output_format, output)
         }
+        _ => write_reports(&reports, output_format, rows, total, output),
+    }
+}
+
+/// Point errors of the transformation into the input file they were found in
+fn with_diagnostic(err: Error, file_path: &str, file_contents: &str) -> Error {
+    match err.downcast_ref::<ChopError>() {
+        Some(chop_error) => anyhow!("{}", chop_error.diagnostic(file_path, file_contents)),
+        None => err,
     }
-    Ok((instruction_count, load_count, store_count))
 }
 
 fn read_file(file_path: &str) -> Result<String> {
@@ -109,12 +163,12 @@ fn read_file(file_path: &str) -> Result<String> {
     Ok(file_contents)
 }
 
-fn extract_module_fields<'a>(wat: &'a Wat) -> Result<&'a [ModuleField<'a>]> {
+fn extract_module_fields<'a>(wat: &'a Wat) -> Result<&'a [ModuleField<'a>], ChopError> {
     match wat {
         Wat::Module(module) => match &module.kind {
             ModuleKind::Text(fields) => Ok(fields),
-            ModuleKind::Binary(_) => Err("ModuleKind is binary"),
+            ModuleKind::Binary(_) => Err("Binary modules"),
         },
-        Wat::Component(_) => Err("Input module is component"),
-    }.map_err(Error::msg).map(|fields| fields.as_slice())
+        Wat::Component(_) => Err("Components"),
+    }.map_err(ChopError::unsupported_feature).map(|fields| fields.as_slice())
 }
@@ -1,14 +1,19 @@
-use std::{env, io};
+use std::rc::Rc;
+use std::fs::File;
+use std::{env, fs, io};
 
 use anyhow::{anyhow, Result};
 
-use chop_up::{OutputFormat, run_analysis, run_split};
+use chop_up::{
+    AbiLayout, AlwaysSplit, CsvRows, ExplainLevel, ModuleFormat, OutputFormat, parse_address_range, run_analysis, run_graph, run_split, run_transaction, run_verify,
+    ShadowStack, SkipSafe, SplitOptions, StaticAnalysis, Transaction,
+};
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     let program_name = args
-        .get(0)
-        .and_then(|name| name.split('/').last())
+        .first()
+        .and_then(|name| name.split('/').next_back())
         .expect("Program name should always be an argument");
 
     let config = parse_config(&args[1..]).map_err(|err| {
@@ -18,89 +23,349 @@ Usage {program_name} [command] [input_file] [opts...]");
     })?;
 
     match config {
-        Config::ChopConfig { file_path, state_size, skip_safe, explain } => run_split(file_path, state_size, skip_safe, explain, &mut io::stdout()),
-        Config::AnalyticsConfig { file_path, output_format } => run_analysis(file_path, output_format)
+        Config::ChopConfig { file_path, options } => run_split(file_path, &options, &mut io::stdout()),
+        Config::AnalyticsConfig { file_paths, output_format, rows, total, output } => match output {
+            Some(path) => run_analysis(&file_paths, output_format, rows, total, &mut File::create(path)?),
+            None => run_analysis(&file_paths, output_format, rows, total, &mut io::stdout()),
+        },
+        Config::GraphConfig { file_path, options, output } => match output {
+            Some(path) => run_graph(file_path, &options, &mut File::create(path)?),
+            None => run_graph(file_path, &options, &mut io::stdout()),
+        },
+        Config::RunConfig { file_path, args } => run_transaction(file_path, &args),
+        Config::VerifyConfig { file_path, transaction, options } => run_verify(file_path, &transaction, &options),
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 enum Config<'a> {
     ChopConfig {
         file_path: &'a str,
-        state_size: usize,
-        skip_safe: bool,
-        explain: bool,
+        options: SplitOptions,
     },
     AnalyticsConfig {
-        file_path: &'a str,
+        file_paths: Vec<&'a str>,
         output_format: OutputFormat,
+        rows: CsvRows,
+        total: bool,
+        output: Option<&'a str>,
+    },
+    GraphConfig {
+        file_path: &'a str,
+        options: SplitOptions,
+        output: Option<&'a str>,
+    },
+    RunConfig {
+        file_path: &'a str,
+        args: Vec<i64>,
+    },
+    VerifyConfig {
+        file_path: &'a str,
+        transaction: Transaction,
+        options: SplitOptions,
     },
 }
 
-fn parse_config(args: &[String]) -> Result<Config> {
-    let subcommand = args.get(0).ok_or(anyhow!("Missing subcommand"))?;
+fn parse_config(args: &[String]) -> Result<Config<'_>> {
+    let subcommand = args.first().ok_or(anyhow!("Missing subcommand"))?;
     let file_path = args.get(1).ok_or(anyhow!("Missing file path"))?;
     match subcommand.as_str() {
         "split" => parse_split_config(file_path, &args[2..]),
-        "analyze" => parse_analytics_config(file_path, &args[2..]),
+        "analyze" => parse_analytics_config(&args[1..]),
+        "graph" => parse_graph_config(file_path, &args[2..]),
+        "run" => parse_run_config(file_path, &args[2..]),
+        "verify" => parse_verify_config(file_path, &args[2..]),
         _ => Err(anyhow!("\
 Unknown command {subcommand}
 Possible commands are:
   split    split transactional code
-  analyze  calculate analytics for wasm code")),
+  analyze  calculate analytics for wasm code
+  graph    draw the control flow of transaction functions and their split points as Graphviz DOT
+  run      run split code, printing the microtransactions it yields
+  verify   check that split code behaves like the original")),
     }
 }
 
-fn parse_split_config<'a>(file_path: &'a str, args: &[String]) -> Result<Config<'a>> {
-    let state_size = args
-        .get(0)
-        .ok_or(anyhow!("Missing state size"))?
-        .parse()
-        .map_err(|_| anyhow!("State size must be a positive integer"))?;
-    let mut skip_safe = false;
-    let mut explain = false;
-
-    for flag in args[1..].iter() {
+fn parse_split_config<'a>(file_path: &'a str, args: &'a [String]) -> Result<Config<'a>> {
+    let mut options = SplitOptions::new(parse_state_size(args)?);
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
         match flag.as_str() {
-            "--skip-safe" => skip_safe = true,
-            "--explain" => explain = true,
+            "--format" => {
+                options.format = match flags.next().ok_or(anyhow!("Missing module format"))?.as_str() {
+                    "text" => ModuleFormat::Text,
+                    "binary" => ModuleFormat::Binary,
+                    unknown_format => return Err(anyhow!("\
+Unknown module format {unknown_format}
+Supported formats:
+  text
+  binary")),
+                };
+            }
+            "--source-map" => {
+                options.source_map_path = Some(flags.next().ok_or(anyhow!("Missing source map path"))?.clone());
+            }
+            "--stats" => options.stats = true,
             _ => {
-                return Err(anyhow!("\
+                if !parse_split_opt(&mut options, flag, &mut flags)? {
+                    return Err(anyhow!("\
 Unknown opt {flag}
 Possible opts are:
-  --skip-safe  optimize splits by skipping accesses to function arguments
-  --explain    add explanatory comments to transformed code")
-                );
+{SPLIT_OPTS_HELP}
+  --format [format]           write the module as text or binary
+  --source-map [path]         write a JSON map from microtransactions to the original code to the given path
+  --stats                     write how the module grew by splitting to stderr")
+                    );
+                }
             }
         }
     }
 
     Ok(Config::ChopConfig {
         file_path,
-        state_size,
-        skip_safe,
-        explain,
+        options,
     })
 }
 
-fn parse_analytics_config<'a>(file_path: &'a str, args: &[String]) -> Result<Config<'a>> {
-    let output_format = match args.get(0)
+const SPLIT_OPTS_HELP: &str = "\
+  --skip-safe                 optimize splits by skipping accesses to function arguments
+  --policy [policy]           decide splits with the always, skip-safe or static-analysis policy
+  --split-globals             split around accesses to mutable globals
+  --explain                   add explanatory comments to transformed code
+  --explain-level [level]     off, annotate, or source to also annotate instructions with their input line
+  --abi-layout [layout]       utx layout as addrs,log2lens,naddr,size,capacity (default 0,28,35,36,7)
+  --only [function]           only split the given function, may be repeated
+  --skip [function]           leave the given function as it is, may be repeated
+  --private-region [range]    addresses start..end private to the transaction, may be repeated
+  --private-global [global]   global pointing into private memory, such as the shadow stack pointer
+  --shadow-stack [global]     global holding the shadow stack pointer, detect or off (the default)";
+
+/// Apply an option shared by every command that splits, returning whether `flag` is one
+fn parse_split_opt<'a>(options: &mut SplitOptions, flag: &str, flags: &mut impl Iterator<Item = &'a String>) -> Result<bool> {
+    match flag {
+        "--skip-safe" => options.policy = Rc::new(SkipSafe),
+        "--policy" => {
+            options.policy = match flags.next().ok_or(anyhow!("Missing split policy"))?.as_str() {
+                "always" => Rc::new(AlwaysSplit),
+                "skip-safe" => Rc::new(SkipSafe),
+                "static-analysis" => Rc::new(StaticAnalysis),
+                unknown_policy => return Err(anyhow!("\
+Unknown split policy {unknown_policy}
+Supported policies:
+  always
+  skip-safe
+  static-analysis")),
+            };
+        }
+        "--split-globals" => options.split_globals = true,
+        "--explain" => options.explain = ExplainLevel::Annotate,
+        "--explain-level" => {
+            options.explain = match flags.next().ok_or(anyhow!("Missing explain level"))?.as_str() {
+                "off" => ExplainLevel::Off,
+                "annotate" => ExplainLevel::Annotate,
+                "source" => ExplainLevel::Source,
+                unknown_level => return Err(anyhow!("\
+Unknown explain level {unknown_level}
+Supported levels:
+  off
+  annotate
+  source")),
+            };
+        }
+        "--abi-layout" => {
+            let layout = flags.next().ok_or(anyhow!("Missing ABI layout"))?;
+            let fields = layout
+                .split(',')
+                .map(|field| field.parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()
+                .map_err(|_| anyhow!("ABI layout fields must be positive integers"))?;
+            let [addrs_offset, log2lens_offset, naddr_offset, size, max_addrs] = fields[..] else {
+                return Err(anyhow!("ABI layout must be given as addrs,log2lens,naddr,size,capacity"));
+            };
+            options.abi = AbiLayout {
+                addrs_offset,
+                log2lens_offset,
+                naddr_offset,
+                size,
+                max_addrs,
+            };
+        }
+        "--only" => {
+            let function = flags.next().ok_or(anyhow!("Missing function name"))?;
+            options.functions.only.push(function.trim_start_matches('$').into());
+        }
+        "--skip" => {
+            let function = flags.next().ok_or(anyhow!("Missing function name"))?;
+            options.functions.skip.push(function.trim_start_matches('$').into());
+        }
+        "--private-region" => {
+            let region = flags.next().ok_or(anyhow!("Missing private region"))?;
+            let region = parse_address_range(region)
+                .ok_or(anyhow!("Private region must be given as start..end, with start below end"))?;
+            options.private_regions.push(region);
+        }
+        "--private-global" => {
+            let global = flags.next().ok_or(anyhow!("Missing global name"))?;
+            options.private_globals.push(global.trim_start_matches('$').into());
+        }
+        "--shadow-stack" => {
+            options.shadow_stack = match flags.next().ok_or(anyhow!("Missing shadow stack pointer"))?.as_str() {
+                "detect" => ShadowStack::Detect,
+                "off" => ShadowStack::Off,
+                global => ShadowStack::Global(global.trim_start_matches('$').into()),
+            };
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_state_size(args: &[String]) -> Result<usize> {
+    args.first()
+        .ok_or(anyhow!("Missing state size"))?
+        .parse()
+        .map_err(|_| anyhow!("State size must be a positive integer"))
+}
+
+fn parse_analytics_config(args: &[String]) -> Result<Config<'_>> {
+    // Every input comes before the output format
+    let format_index = args
+        .iter()
+        .position(|arg| matches!(arg.as_str(), "standard" | "csv" | "json") || arg.starts_with("--"))
+        .unwrap_or(args.len());
+    let file_paths = args[..format_index].iter().map(String::as_str).collect::<Vec<&str>>();
+    let output_format = match args.get(format_index)
         .ok_or(anyhow!("Missing output format"))?
         .as_str() {
         "standard" => OutputFormat::Normal,
         "csv" => OutputFormat::CSV,
+        "json" => OutputFormat::JSON,
         unknown_format => return Err(anyhow!("\
 Unknown output format {unknown_format}
 Supported formats:
   standard
-  csv"))
+  csv
+  json"))
     };
+    let mut output = None;
+    let mut rows = CsvRows::default();
+    let mut total = false;
 
-    if let Some(flag) = args[1..].first() {
-        return Err(anyhow!("Unknown opt {flag}"));
+    let mut flags = args[format_index + 1..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--output" => output = Some(flags.next().ok_or(anyhow!("Missing output path"))?.as_str()),
+            "--rows" => {
+                rows = match flags.next().ok_or(anyhow!("Missing CSV rows"))?.as_str() {
+                    "file" => CsvRows::File,
+                    "function" => CsvRows::Function,
+                    unknown_rows => return Err(anyhow!("\
+Unknown CSV rows {unknown_rows}
+Supported rows:
+  file
+  function")),
+                };
+            }
+            "--total" => total = true,
+            _ => return Err(anyhow!("\
+Unknown opt {flag}
+Supported opts:
+  --output [path]             write the analysis to the given path instead of stdout
+  --rows [rows]               write a CSV row per file (the default) or per function
+  --total                     end CSV with a row of totals")),
+        }
     }
 
     Ok(Config::AnalyticsConfig {
-        file_path,
+        file_paths,
         output_format,
+        rows,
+        total,
+        output,
+    })
+}
+
+fn parse_graph_config<'a>(file_path: &'a str, args: &'a [String]) -> Result<Config<'a>> {
+    let mut options = SplitOptions::new(parse_state_size(args)?);
+    let mut output = None;
+
+    let mut flags = args[1..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--output" => output = Some(flags.next().ok_or(anyhow!("Missing output path"))?.as_str()),
+            _ => {
+                if !parse_split_opt(&mut options, flag, &mut flags)? {
+                    return Err(anyhow!("\
+Unknown opt {flag}
+Possible opts are:
+{SPLIT_OPTS_HELP}
+  --output [path]             write the graph to the given path instead of stdout")
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(Config::GraphConfig {
+        file_path,
+        options,
+        output,
+    })
+}
+
+fn parse_run_config<'a>(file_path: &'a str, args: &[String]) -> Result<Config<'a>> {
+    let args = args
+        .iter()
+        .map(|arg| arg.parse().map_err(|_| anyhow!("Transaction argument {arg} is not an integer")))
+        .collect::<Result<Vec<i64>>>()?;
+
+    Ok(Config::RunConfig {
+        file_path,
+        args,
+    })
+}
+
+fn parse_verify_config<'a>(file_path: &'a str, args: &[String]) -> Result<Config<'a>> {
+    let mut options = SplitOptions::new(parse_state_size(args)?);
+    let function = args.get(1).ok_or(anyhow!("Missing function name"))?;
+    let [tx, utx, state] = [("tx", 2), ("utx", 3), ("state", 4)].map(|(name, i)| {
+        args.get(i)
+            .ok_or(anyhow!("Missing {name} address"))?
+            .parse::<u32>()
+            .map_err(|_| anyhow!("The {name} address must be a positive integer"))
+    });
+    let mut transaction = Transaction {
+        function: function.trim_start_matches('$').into(),
+        tx: tx?,
+        utx: utx?,
+        state: state?,
+        memory: Vec::default(),
+    };
+
+    let mut flags = args.iter().skip(5);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--memory" => {
+                let path = flags.next().ok_or(anyhow!("Missing memory image path"))?;
+                transaction.memory = fs::read(path)
+                    .map_err(|err| anyhow!("Failed to read memory image: {err}"))?;
+            }
+            _ => {
+                if !parse_split_opt(&mut options, flag, &mut flags)? {
+                    return Err(anyhow!("\
+Unknown opt {flag}
+Possible opts are:
+{SPLIT_OPTS_HELP}
+  --memory [image]            initial memory contents, loaded at address 0")
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(Config::VerifyConfig {
+        file_path,
+        transaction,
+        options,
     })
 }
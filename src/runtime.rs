@@ -0,0 +1,301 @@
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Result};
+use wasmi::core::ValueType;
+use wasmi::{Caller, Engine, Func, Instance, Linker, Memory, Module, Store, Value};
+use wast::parser::{parse, ParseBuffer};
+use wast::Wat;
+
+use crate::chop_up::AbiLayout;
+use crate::instrument::{RECORD_FUNCTION, RECORD_MODULE};
+
+/// Upper bound on the number of microtransactions a single transaction may be split into,
+/// guarding against transactions that never return NULL.
+const MAX_STEPS: usize = 1_000_000;
+
+/// A single microtransaction as yielded to the runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utx {
+    pub naddr: u8,
+    pub addrs: Vec<u64>,
+    pub log2lens: Vec<u8>,
+}
+
+impl Display for Utx {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "utx {{")?;
+        writeln!(f, "  naddr    = {}", self.naddr)?;
+        for (i, (addr, log2len)) in self.addrs.iter().zip(&self.log2lens).enumerate() {
+            writeln!(f, "  addrs[{i}] = {addr} , log2lens[{i}] = {log2len}")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// The ordered sequence of microtransactions yielded while running a transaction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub utxs: Vec<Utx>,
+    /// Value returned by the last microtransaction, NULL unless the transaction returns something else
+    pub result: u32,
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for utx in &self.utxs {
+            writeln!(f, "{utx}")?;
+        }
+        write!(f, "steps = {}", self.utxs.len())
+    }
+}
+
+/// An access reported by a module instrumented for verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedAccess {
+    /// Index of the instruction making the access
+    pub site: u32,
+    /// Address before the offset of the instruction is applied
+    pub address: u64,
+    /// Microtransaction the access is made in, counting from the first one at 0
+    pub step: usize,
+}
+
+#[derive(Default)]
+struct Recording {
+    step: usize,
+    accesses: Vec<RecordedAccess>,
+}
+
+/// Reference implementation of the microtransactional runtime.
+///
+/// Mirrors the C harnesses in `wasm/runtime`,
+/// driving a split module through the `__enter`/`__step` ABI
+/// and reading each utx back through the `__get_utx_*` exports.
+pub struct Runtime {
+    store: Store<Recording>,
+    instance: Instance,
+    abi: AbiLayout,
+    /// Table indices of microtransactions, any other value returned ends the transaction
+    microtransactions: Option<Vec<u32>>,
+}
+
+impl Runtime {
+    pub fn from_wat(input: &str) -> Result<Self> {
+        let buffer = ParseBuffer::new(input)?;
+        let mut wat = parse::<Wat>(&buffer)?;
+        Self::from_binary(&wat.encode()?)
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<Self> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, binary)
+            .map_err(|err| anyhow!("Failed to load module: {err}"))?;
+        let mut store = Store::new(&engine, Recording::default());
+        let mut linker = Linker::<Recording>::new(&engine);
+        linker
+            .func_wrap(RECORD_MODULE, RECORD_FUNCTION, |mut caller: Caller<Recording>, address: i64, site: i32| {
+                let recording = caller.data_mut();
+                let step = recording.step;
+                recording.accesses.push(RecordedAccess {
+                    site: site as u32,
+                    address: address as u64,
+                    step,
+                });
+            })
+            .map_err(|err| anyhow!("Failed to define the access recorder: {err}"))?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|err| anyhow!("Failed to instantiate module: {err}"))?;
+        Ok(Self {
+            store,
+            instance,
+            abi: AbiLayout::default(),
+            microtransactions: None,
+        })
+    }
+
+    /// Read utxs written by the split module according to `abi` instead of the default layout
+    pub fn with_abi(mut self, abi: AbiLayout) -> Self {
+        self.abi = abi;
+        self
+    }
+
+    /// Only follow the chain through the table indices of microtransactions,
+    /// taking any other value returned as the result of the transaction instead of NULL
+    pub fn with_microtransactions(mut self, table_indices: impl IntoIterator<Item = usize>) -> Self {
+        self.microtransactions = Some(table_indices.into_iter().map(|index| index as u32).collect());
+        self
+    }
+
+    /// Accesses reported by an instrumented module since the last call, in the order they were made
+    pub fn take_accesses(&mut self) -> Vec<RecordedAccess> {
+        std::mem::take(&mut self.store.data_mut().accesses)
+    }
+
+    /// Enter a transaction with the given arguments and step through it until it returns NULL
+    pub fn run_transaction(&mut self, args: &[i64]) -> Result<Trace> {
+        let enter = self.export_func("__enter")?;
+        let args = enter
+            .ty(&self.store)
+            .params()
+            .iter()
+            .zip(args)
+            .map(|(ty, &arg)| match ty {
+                ValueType::I32 => Ok(Value::I32(arg as i32)),
+                ValueType::I64 => Ok(Value::I64(arg)),
+                _ => Err(anyhow!("__enter may only take integer arguments")),
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        let mut callsite = self.call(enter, &args)?;
+
+        let step = self.export_func("__step")?;
+        let mut trace = Trace::default();
+        while callsite != 0 {
+            if trace.utxs.len() == MAX_STEPS {
+                return Err(anyhow!("Transaction did not finish within {MAX_STEPS} steps"));
+            }
+            trace.utxs.push(self.read_utx()?);
+            callsite = self.call(step, &[Value::I32(callsite as i32)])?;
+        }
+        Ok(trace)
+    }
+
+    /// Call a transaction function directly, returning its result
+    pub fn call_transaction(&mut self, function: &str, tx: u32, utx: u32, state: u32) -> Result<u32> {
+        let args = [tx, utx, state].map(|arg| Value::I32(arg as i32));
+        Ok(self.call(self.export_func(function)?, &args)? as u32)
+    }
+
+    /// Call a transaction function directly and follow the chain of microtransactions
+    /// through the first exported table until NULL, or a value that is no microtransaction, is returned.
+    /// Every utx is read straight from the exported memory at the `utx` argument.
+    pub fn run_microtransactions(&mut self, function: &str, tx: u32, utx: u32, state: u32) -> Result<Trace> {
+        let args = [tx, utx, state].map(|arg| Value::I32(arg as i32));
+        self.store.data_mut().step = 0;
+        let mut callsite = self.call_transaction(function, tx, utx, state)?;
+
+        let mut trace = Trace::default();
+        while self.continues_at(callsite) {
+            if trace.utxs.len() == MAX_STEPS {
+                return Err(anyhow!("Transaction did not finish within {MAX_STEPS} steps"));
+            }
+            trace.utxs.push(self.read_utx_from_memory(utx as usize)?);
+            let table = self
+                .instance
+                .exports(&self.store)
+                .find_map(|export| export.into_table())
+                .ok_or(anyhow!("Module does not export a table"))?;
+            let next = match table.get(&self.store, callsite) {
+                Some(Value::FuncRef(func_ref)) => func_ref.func().copied(),
+                _ => None,
+            }
+            .ok_or(anyhow!("Table entry {callsite} is not a function"))?;
+            self.store.data_mut().step = trace.utxs.len();
+            callsite = self.call(next, &args)? as u32;
+        }
+        trace.result = callsite;
+        Ok(trace)
+    }
+
+    fn continues_at(&self, callsite: u32) -> bool {
+        match &self.microtransactions {
+            Some(table_indices) => table_indices.contains(&callsite),
+            None => callsite != 0,
+        }
+    }
+
+    /// Contents of the first exported memory
+    pub fn memory(&self) -> Result<&[u8]> {
+        Ok(self.export_memory()?.data(&self.store))
+    }
+
+    pub fn write_memory(&mut self, offset: usize, bytes: &[u8]) -> Result<()> {
+        self.export_memory()?
+            .data_mut(&mut self.store)
+            .get_mut(offset..offset + bytes.len())
+            .ok_or(anyhow!("Write of {} bytes at {offset} is out of bounds", bytes.len()))?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read_utx_from_memory(&self, utx: usize) -> Result<Utx> {
+        let [addrs_offset, log2lens_offset, naddr_offset, size] = [
+            self.abi.addrs_offset,
+            self.abi.log2lens_offset,
+            self.abi.naddr_offset,
+            self.abi.size,
+        ]
+        .map(|offset| offset as usize);
+        let bytes = self
+            .memory()?
+            .get(utx..utx + size)
+            .ok_or(anyhow!("utx at {utx} is out of bounds"))?;
+        let naddr = *bytes
+            .get(naddr_offset)
+            .ok_or(anyhow!("naddr is outside of the utx"))?;
+        if naddr as u32 > self.abi.max_addrs {
+            return Err(anyhow!("utx at {utx} claims {naddr} addresses"));
+        }
+        let addrs = (0..naddr as usize)
+            .map(|i| {
+                let start = addrs_offset + i * 4;
+                bytes
+                    .get(start..start + 4)
+                    .map(|addr| u32::from_le_bytes(addr.try_into().unwrap()) as u64)
+            })
+            .collect::<Option<Vec<u64>>>()
+            .ok_or(anyhow!("addrs are outside of the utx"))?;
+        let log2lens = bytes
+            .get(log2lens_offset..log2lens_offset + naddr as usize)
+            .ok_or(anyhow!("log2lens are outside of the utx"))?
+            .to_vec();
+        Ok(Utx {
+            naddr,
+            addrs,
+            log2lens,
+        })
+    }
+
+    fn read_utx(&mut self) -> Result<Utx> {
+        let naddr = self.call(self.export_func("__get_utx_naddr")?, &[])? as u8;
+        let get_addrs = self.export_func("__get_utx_addrs")?;
+        let get_log2lens = self.export_func("__get_utx_log2lens")?;
+        let mut addrs = Vec::default();
+        let mut log2lens = Vec::default();
+        for i in 0..naddr {
+            addrs.push(self.call(get_addrs, &[Value::I32(i.into())])?);
+            log2lens.push(self.call(get_log2lens, &[Value::I32(i.into())])? as u8);
+        }
+        Ok(Utx {
+            naddr,
+            addrs,
+            log2lens,
+        })
+    }
+
+    /// Call a function returning a single integer, zero-extending it
+    fn call(&mut self, func: Func, args: &[Value]) -> Result<u64> {
+        let mut results = [Value::I32(0)];
+        func.call(&mut self.store, args, &mut results)
+            .map_err(|err| anyhow!("Call failed: {err}"))?;
+        match results[0] {
+            Value::I32(value) => Ok(value as u32 as u64),
+            Value::I64(value) => Ok(value as u64),
+            _ => Err(anyhow!("Expected an integer result")),
+        }
+    }
+
+    fn export_memory(&self) -> Result<Memory> {
+        self.instance
+            .exports(&self.store)
+            .find_map(|export| export.into_memory())
+            .ok_or(anyhow!("Module does not export a memory"))
+    }
+
+    fn export_func(&self, name: &str) -> Result<Func> {
+        self.instance
+            .get_func(&self.store, name)
+            .ok_or(anyhow!("Module does not export {name}"))
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use wast::core::{FuncKind, ItemKind, ModuleField};
+use wast::parser::{parse, ParseBuffer};
+use wast::Wat;
+
+use crate::chop_up::{ModuleContext, TransformOutput};
+use crate::extract_module_fields;
+
+/// How a module grew by being split
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SplitStats {
+    /// Size of the original module in the binary format, in bytes
+    pub original_size: usize,
+    /// Size of the split module in the binary format, in bytes
+    pub split_size: usize,
+    /// Table entries taken up by microtransactions
+    pub table_entries: usize,
+    /// Every transaction function, by name
+    pub functions: Vec<FunctionStats>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FunctionStats {
+    pub name: String,
+    /// Instructions of the function before splitting
+    pub original_instructions: usize,
+    /// Instructions of every microtransaction split from the function, by name in table order
+    pub microtransactions: Vec<(String, usize)>,
+    /// Instructions the transformation added to save and restore the stack, locals and store value across splits
+    pub added_instructions: usize,
+}
+
+impl SplitStats {
+    /// Size of the split module relative to the original one, if the original one has a size
+    pub fn blowup(&self) -> Option<f64> {
+        (self.original_size > 0).then(|| self.split_size as f64 / self.original_size as f64)
+    }
+
+    pub fn write(&self, output: &mut dyn Write) -> Result<()> {
+        writeln!(output, "\
+Module size = {original_size} -> {split_size} bytes{blowup}
+Table entries = {table_entries}",
+                 original_size = self.original_size,
+                 split_size = self.split_size,
+                 blowup = self.blowup().map(|blowup| format!(" ({blowup:.2}x)")).unwrap_or_default(),
+                 table_entries = self.table_entries)?;
+        for function in &self.functions {
+            writeln!(output, "\
+Transaction ${name}
+  Instructions = {original_instructions} -> {split_instructions}
+  Added instructions = {added_instructions}
+  Microtransactions:",
+                     name = function.name,
+                     original_instructions = function.original_instructions,
+                     split_instructions = function.split_instructions(),
+                     added_instructions = function.added_instructions)?;
+            for (name, instruction_count) in &function.microtransactions {
+                writeln!(output, "    {:<24} {:>6}", format!("${name}"), instruction_count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FunctionStats {
+    /// Instructions of all microtransactions of the function
+    pub fn split_instructions(&self) -> usize {
+        self.microtransactions.iter().map(|(_, instruction_count)| instruction_count).sum()
+    }
+}
+
+/// Compare the module `input` to the result of splitting it
+pub fn split_stats(input: &str, output: &TransformOutput) -> Result<SplitStats> {
+    let (original_size, original_instructions) = measure_module(input)?;
+    let (split_size, split_instructions) = measure_module(&output.module)?;
+    let count = |instructions: &BTreeMap<String, usize>, name: &str| instructions.get(name).copied().unwrap_or(0);
+    let functions = output
+        .split_counts
+        .keys()
+        .map(|name| {
+            let microtransactions = output
+                .microtransactions
+                .iter()
+                .filter(|microtransaction| &microtransaction.function == name);
+            FunctionStats {
+                name: name.clone(),
+                original_instructions: count(&original_instructions, name),
+                microtransactions: microtransactions
+                    .clone()
+                    .map(|microtransaction| {
+                        (microtransaction.name.clone(), count(&split_instructions, &microtransaction.name))
+                    })
+                    .collect(),
+                added_instructions: microtransactions
+                    .map(|microtransaction| count(&output.state_instructions, &microtransaction.name))
+                    .sum(),
+            }
+        })
+        .collect();
+    Ok(SplitStats {
+        original_size,
+        split_size,
+        table_entries: output.microtransactions.len(),
+        functions,
+    })
+}
+
+/// Size of a module in the binary format, and the instruction count of every function it defines
+fn measure_module(input: &str) -> Result<(usize, BTreeMap<String, usize>)> {
+    let buffer = ParseBuffer::new(input)?;
+    let mut wat = parse::<Wat>(&buffer)?;
+    let fields = extract_module_fields(&wat)?;
+    let context = ModuleContext::from_module_fields(fields)?;
+    let mut instruction_counts = BTreeMap::default();
+    let mut function_index = 0;
+    for field in fields {
+        match field {
+            ModuleField::Import(import) if matches!(import.item.kind, ItemKind::Func(_)) => function_index += 1,
+            ModuleField::Func(func) => {
+                let FuncKind::Inline { expression, .. } = &func.kind else {
+                    return Err(anyhow!("FuncKind is not inline"));
+                };
+                instruction_counts.insert(context.function_name(function_index), expression.instrs.len());
+                function_index += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok((wat.encode()?.len(), instruction_counts))
+}
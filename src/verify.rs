@@ -0,0 +1,266 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use wast::parser::{parse, ParseBuffer};
+use wast::Wat;
+
+use crate::chop_up::{transform_wat, SplitOptions, TransformOutput};
+use crate::instrument::{instrument, AccessKind, Instrumented, ENTRY_EXPORT};
+use crate::runtime::{RecordedAccess, Runtime};
+
+/// A single invocation of a transaction function
+#[derive(Clone, Debug, Default)]
+pub struct Transaction {
+    pub function: String,
+    pub tx: u32,
+    pub utx: u32,
+    pub state: u32,
+    /// Written to memory at address 0 after data segments are applied
+    pub memory: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// The memory accesses performed differ, `position` is the index into the sequence of accesses performed
+    PerformedAccess {
+        position: usize,
+        original: Option<u64>,
+        split: Option<u64>,
+    },
+    /// An access is performed without its address in the utx yielded before the microtransaction performing it,
+    /// `position` is the index into the sequence of accesses performed
+    Undeclared {
+        position: usize,
+        address: u64,
+    },
+    /// The addresses yielded differ from those of the accesses split at,
+    /// `position` is the index into the sequence of addresses yielded
+    Access {
+        position: usize,
+        original: Option<u64>,
+        split: Option<u64>,
+    },
+    Memory {
+        address: usize,
+        original: u8,
+        split: u8,
+    },
+    ReturnValue {
+        original: u32,
+        split: u32,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let show = |address: &Option<u64>| address.map_or("nothing".into(), |address| address.to_string());
+        match self {
+            Divergence::PerformedAccess { position, original, split } => write!(
+                f,
+                "performed access {position} differs - original accesses {}, split accesses {}",
+                show(original),
+                show(split)
+            ),
+            Divergence::Undeclared { position, address } => write!(
+                f,
+                "performed access {position} to {address} is not yielded before it"
+            ),
+            Divergence::Access { position, original, split } => write!(
+                f,
+                "access {position} differs - original accesses {}, split yields {}",
+                show(original),
+                show(split)
+            ),
+            Divergence::Memory { address, original, split } => write!(
+                f,
+                "memory differs at {address} - original {original:#04x}, split {split:#04x}"
+            ),
+            Divergence::ReturnValue { original, split } => write!(
+                f,
+                "return value differs - original {original}, split {split}"
+            ),
+        }
+    }
+}
+
+/// Outcome of running a transaction both as written and as a chain of microtransactions
+#[derive(Clone, Debug)]
+pub struct Verification {
+    /// Accesses made by the original transaction, accesses to globals included if they are split at
+    pub original_accesses: Vec<u64>,
+    /// Memory accesses performed by the split transaction, besides those to its utx and saved values
+    pub performed_accesses: Vec<u64>,
+    /// Addresses yielded by the split transaction
+    pub split_accesses: Vec<u64>,
+    pub divergence: Option<Divergence>,
+}
+
+impl Verification {
+    pub fn is_equivalent(&self) -> bool {
+        self.divergence.is_none()
+    }
+}
+
+impl Display for Verification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "original accesses  = {:?}", self.original_accesses)?;
+        writeln!(f, "performed accesses = {:?}", self.performed_accesses)?;
+        writeln!(f, "split accesses     = {:?}", self.split_accesses)?;
+        match &self.divergence {
+            Some(divergence) => write!(f, "Divergence: {divergence}"),
+            None => write!(f, "Equivalent"),
+        }
+    }
+}
+
+/// Split `input` and check the result against the original for a single transaction, see [`verify_output`]
+pub fn verify(
+    input: &str,
+    transaction: &Transaction,
+    options: &SplitOptions,
+) -> Result<Verification> {
+    let buffer = ParseBuffer::new(input)?;
+    let wat = parse::<Wat>(&buffer)?;
+    let output = transform_wat(&wat, &input.split('\n').collect::<Vec<&str>>(), options)?;
+    verify_output(input, &output, transaction, options)
+}
+
+/// Check the result of splitting `input` against the original for a single transaction.
+///
+/// Both modules are instrumented to report their accesses, the original is run on its own
+/// and the split one as a chain of microtransactions in the [`Runtime`].
+/// They must perform the same memory accesses in the same order, leaving memory and the result the same.
+/// Every access split at must be yielded in the utx before the microtransaction performing it,
+/// and nothing else may be yielded.
+/// Memory used by the utx and by values saved across splits is excluded from the comparison.
+pub fn verify_output(
+    input: &str,
+    output: &TransformOutput,
+    transaction: &Transaction,
+    options: &SplitOptions,
+) -> Result<Verification> {
+    let Transaction { function, tx, utx, state, memory } = transaction;
+
+    let original = instrument(input, function, options.split_globals)?;
+    let mut runtime = Runtime::from_binary(&original.binary)?;
+    runtime.write_memory(0, memory)?;
+    runtime.take_accesses();
+    let original_result = runtime.call_transaction(ENTRY_EXPORT, *tx, *utx, *state)?;
+    let original_accesses = runtime.take_accesses();
+    let original_memory = runtime.memory()?.to_vec();
+
+    let split = instrument(&output.module, function, false)?;
+    let mut runtime = Runtime::from_binary(&split.binary)?
+        .with_abi(options.abi.clone())
+        .with_microtransactions(output.microtransactions.iter().map(|microtransaction| microtransaction.table_index));
+    runtime.write_memory(0, memory)?;
+    runtime.take_accesses();
+    let trace = runtime.run_microtransactions(ENTRY_EXPORT, *tx, *utx, *state)?;
+
+    let scratch_start = *state as usize + options.state_size;
+    let excluded = [
+        *utx as u64..*utx as u64 + options.abi.size as u64,
+        scratch_start as u64..(scratch_start + output.state_usage) as u64,
+    ];
+    let performed = runtime
+        .take_accesses()
+        .into_iter()
+        .filter(|access| !excluded.iter().any(|range| range.contains(&address(&split, access))))
+        .collect::<Vec<RecordedAccess>>();
+    let is_declared = |access: &RecordedAccess| {
+        let site = &original.sites[access.site as usize];
+        output
+            .declared_accesses
+            .get(&site.function)
+            .is_some_and(|positions| positions.contains(&site.position))
+    };
+    let is_memory = |access: &&RecordedAccess| original.sites[access.site as usize].kind != AccessKind::Global;
+
+    let original_performed = original_accesses
+        .iter()
+        .filter(is_memory)
+        .map(|access| address(&original, access))
+        .collect::<Vec<u64>>();
+    let performed_accesses = performed.iter().map(|access| address(&split, access)).collect::<Vec<u64>>();
+    let declared = original_accesses
+        .iter()
+        .filter(|access| is_declared(access))
+        .map(|access| address(&original, access))
+        .collect::<Vec<u64>>();
+    let split_accesses = trace
+        .utxs
+        .iter()
+        .flat_map(|utx| utx.addrs.iter().copied())
+        .collect::<Vec<u64>>();
+
+    let undeclared = original_accesses
+        .iter()
+        .filter(is_memory)
+        .zip(&performed)
+        .enumerate()
+        .find(|(_, (original, performed))| {
+            let address = address(&split, performed);
+            let yielded = performed
+                .step
+                .checked_sub(1)
+                .and_then(|step| trace.utxs.get(step))
+                .is_some_and(|utx| utx.addrs.contains(&address));
+            is_declared(original) && !yielded
+        })
+        .map(|(position, (_, performed))| Divergence::Undeclared {
+            position,
+            address: address(&split, performed),
+        });
+    let divergence = sequence_divergence(&original_performed, &performed_accesses)
+        .map(|(position, original, split)| Divergence::PerformedAccess { position, original, split })
+        .or(undeclared)
+        .or(sequence_divergence(&declared, &split_accesses)
+            .map(|(position, original, split)| Divergence::Access { position, original, split }))
+        .or(memory_divergence(&original_memory, runtime.memory()?, &excluded)?)
+        .or((original_result != trace.result).then_some(Divergence::ReturnValue {
+            original: original_result,
+            split: trace.result,
+        }));
+
+    Ok(Verification {
+        original_accesses: original_accesses.iter().map(|access| address(&original, access)).collect(),
+        performed_accesses,
+        split_accesses,
+        divergence,
+    })
+}
+
+/// The address an access reported by an instrumented module is made at
+fn address(instrumented: &Instrumented, access: &RecordedAccess) -> u64 {
+    access.address + instrumented.sites[access.site as usize].offset
+}
+
+/// The first position two sequences differ at, with the values there
+fn sequence_divergence(original: &[u64], split: &[u64]) -> Option<(usize, Option<u64>, Option<u64>)> {
+    (0..original.len().max(split.len()))
+        .map(|position| (position, original.get(position).copied(), split.get(position).copied()))
+        .find(|(_, original, split)| original != split)
+}
+
+fn memory_divergence(
+    original: &[u8],
+    split: &[u8],
+    excluded: &[Range<u64>],
+) -> Result<Option<Divergence>> {
+    if split.len() < original.len() {
+        return Err(anyhow!("Split module has less memory than the original"));
+    }
+    Ok(original
+        .iter()
+        .zip(split)
+        .enumerate()
+        .find(|(address, (original, split))| {
+            original != split && !excluded.iter().any(|range| range.contains(&(*address as u64)))
+        })
+        .map(|(address, (&original, &split))| Divergence::Memory {
+            address,
+            original,
+            split,
+        }))
+}
@@ -0,0 +1,198 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{analyze_wat, write_reports, AnalysisReport, CsvRows, FunctionAnalysis, OutputFormat};
+
+#[test]
+fn split_points() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        (block
+            local.get $tx
+            i32.load
+            br_if 0
+            i32.const 64
+            i32.load
+            drop
+        )
+        i32.const 128
+        i32.load
+    )
+    (func $__step (param i32) (result i32)
+        local.get 0
+        i32.load
+    )
+    (memory 1)
+)";
+    assert_eq!(
+        analyze_wat(input).unwrap(),
+        AnalysisReport {
+            file: None,
+            size: input.len(),
+            instruction_count: 10,
+            load_count: 3,
+            store_count: 0,
+            functions: vec![
+                FunctionAnalysis {
+                    name: "transfer".into(),
+                    instruction_count: 10,
+                    load_count: 3,
+                    store_count: 0,
+                    ignored: false,
+                    split_points: 3,
+                    // The load through $tx is not split
+                    skip_safe_split_points: 2,
+                    // Branching out of the block skips the split before the load from 64
+                    max_chain_length: 4,
+                    saved_state: vec![16, 16, 16],
+                },
+                FunctionAnalysis {
+                    name: "__step".into(),
+                    instruction_count: 2,
+                    load_count: 1,
+                    store_count: 0,
+                    ignored: true,
+                    ..FunctionAnalysis::default()
+                },
+            ],
+            split_error: None,
+        }
+    );
+}
+
+#[test]
+fn split_error() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (loop
+            i32.const 64
+            i32.load
+            drop
+        )
+        i32.const 0
+    )
+)";
+    let analysis = analyze_wat(input).unwrap();
+    assert_eq!(analysis.load_count, 1);
+    assert_eq!(analysis.functions[0].split_points, 0);
+    assert_eq!(analysis.split_error.as_deref(), Some("Unsupported instruction Loop in function $transfer"));
+
+    // CSV rows show the split figures are missing rather than 0 split points
+    let reports = [AnalysisReport { file: Some("loop.wat".into()), ..analysis }];
+    let mut output = Vec::new();
+    write_reports(&reports, OutputFormat::CSV, CsvRows::Function, true, &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+file,function,instructions,load_instructions,store_instructions,\
+split_points,skip_safe_split_points,max_chain_length,max_saved_state,ignored,split_error
+loop.wat,transfer,6,1,0,0,0,0,0,no,Unsupported instruction Loop in function $transfer
+total,,6,1,0,0,0,0,0,,1 of 1 modules could not be split
+"
+    );
+}
+
+#[test]
+fn ignored_functions() {
+    let input = "\
+(module
+    (import \"env\" \"log\" (func (param i32)))
+    (func (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 64
+        i32.load
+    )
+    (func $helper (param $tx i32) (param $utx i32) (param $state i32) (result i32) ;; @chop:nosplit
+        i32.const 64
+        i32.load
+    )
+    (memory 1)
+)";
+    let analysis = analyze_wat(input).unwrap();
+    let functions = analysis
+        .functions
+        .iter()
+        .map(|function| (function.name.as_str(), function.split_points, function.ignored))
+        .collect::<Vec<_>>();
+    // Functions without an id are named after their index, counting imported functions
+    assert_eq!(functions, vec![("func_1", 1, false), ("helper", 0, true)]);
+    assert_eq!(analysis.instruction_count, 2);
+}
+
+#[test]
+fn json_report() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+    )
+    (memory 1)
+)";
+    let mut output = Vec::new();
+    analyze_wat(input).unwrap().write(OutputFormat::JSON, &mut output).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(report["load_count"], 1);
+    assert_eq!(report["functions"][0]["name"], "transfer");
+    assert_eq!(report["functions"][0]["split_points"], 1);
+    assert_eq!(report["functions"][0]["skip_safe_split_points"], 0);
+    assert_eq!(report["split_error"], serde_json::Value::Null);
+}
+
+#[test]
+fn several_reports() {
+    let report = |file: &str, input: &str| AnalysisReport {
+        file: Some(file.into()),
+        ..analyze_wat(input).unwrap()
+    };
+    let reports = [
+        report(
+            "load.wat",
+            "\
+(module
+    (func $load (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+    )
+)",
+        ),
+        report(
+            "store.wat",
+            "\
+(module
+    (func $store (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 64
+        i32.const 1
+        i32.store
+        i32.const 0
+    )
+)",
+        ),
+    ];
+    let write = |rows| {
+        let mut output = Vec::new();
+        write_reports(&reports, OutputFormat::CSV, rows, true, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+    assert_eq!(
+        write(CsvRows::File),
+        "\
+file,size,total_instructions,normal_instructions,memory_instructions,load_instructions,store_instructions,\
+split_points,skip_safe_split_points,max_chain_length,max_saved_state,split_error
+load.wat,135,2,1,1,1,0,1,0,2,8,
+store.wat,176,4,3,1,0,1,1,1,2,8,
+total,311,6,4,2,1,1,2,1,2,8,
+"
+    );
+    assert_eq!(
+        write(CsvRows::Function),
+        "\
+file,function,instructions,load_instructions,store_instructions,\
+split_points,skip_safe_split_points,max_chain_length,max_saved_state,ignored,split_error
+load.wat,load,2,1,0,1,0,2,8,no,
+store.wat,store,4,0,1,1,1,2,8,no,
+total,,6,1,1,2,1,2,8,,
+"
+    );
+}
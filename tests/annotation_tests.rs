@@ -0,0 +1,174 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{split_wat_string, transform_wat_string, verify_wat_string, ChopError, SplitOptions, Transaction};
+
+mod utils;
+
+#[test]
+fn nosplit() {
+    utils::test_transform(
+        "\
+(module
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load ;; @chop:nosplit
+        i32.load
+        drop
+        i32.const 0
+    )
+    (func $helper (param $tx i32) (param $utx i32) (param $state i32) (result i32) ;; @chop:nosplit
+        local.get $tx
+        i32.load
+    )
+    (memory 1)
+)",
+        "\
+(module
+    (func $transaction (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $tx
+        i32.load ;; @chop:nosplit
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 2
+    )
+    (func $helper (param $tx i32) (param $utx i32) (param $state i32) (result i32) ;; @chop:nosplit
+        local.get $tx
+        i32.load
+    )
+    (func $transaction_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.load
+        i32.load
+        drop
+        i32.const 0
+    )
+    (table 3 funcref)
+    (elem (i32.const 1) func $transaction $transaction_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn private_region() {
+    let input = "\
+(module
+    ;; @chop:private-region 0x1000..0x2000
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 4096
+        i32.load offset=4
+        i32.const 8190
+        i32.load
+        i32.add
+        drop
+        i32.const 0
+    )
+    (memory 1)
+)";
+    // Only the load reaching past the end of the region is split
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    assert_eq!(output.split_counts["transaction"], 1);
+    assert_eq!(output.unsplit_accesses, 1);
+    assert_eq!(output.source_map.microtransactions[1].culprit.as_ref().unwrap().line, 7);
+    // The private load is performed in the first microtransaction, before the split
+    assert!(output.module.contains("\
+        i32.const 4096
+        i32.load offset=4
+        i32.const 8190
+        local.set $memory_address"));
+
+    let verification = verify_wat_string(input, &transaction(), &SplitOptions::new(6)).unwrap();
+    assert_eq!(verification.original_accesses, vec![4100, 8190]);
+    assert_eq!(verification.performed_accesses, vec![4100, 8190]);
+    assert_eq!(verification.split_accesses, vec![8190]);
+    assert!(verification.is_equivalent());
+}
+
+#[test]
+fn private_region_bounded_address() {
+    let input = "\
+(module
+    ;; @chop:private-region 0x1000..0x1400
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load8_u
+        i32.const 2
+        i32.shl
+        i32.const 4096
+        i32.add
+        i32.load
+    )
+    (memory 1)
+)";
+    // The byte read through $tx is split, the load it indexes stays within the region whatever the byte
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    assert_eq!(output.split_counts["transaction"], 1);
+    assert_eq!(output.unsplit_accesses, 1);
+
+    let mut transaction = transaction();
+    transaction.memory = vec![0; 1025];
+    transaction.memory[1024] = 255;
+    let verification = verify_wat_string(input, &transaction, &SplitOptions::new(6)).unwrap();
+    assert_eq!(verification.performed_accesses, vec![1024, 5116]);
+    assert_eq!(verification.split_accesses, vec![1024]);
+    assert!(verification.is_equivalent());
+}
+
+fn transaction() -> Transaction {
+    Transaction {
+        function: "transaction".into(),
+        tx: 1024,
+        utx: 3000,
+        state: 3100,
+        memory: Vec::default(),
+    }
+}
+
+#[test]
+fn invalid_annotation() {
+    let input = "\
+(module
+    ;; @chop:private-region 0x2000..0x1000
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
+    )
+)";
+    let err = transform_wat_string(input, &mut Vec::new(), &SplitOptions::new(6)).unwrap_err();
+    assert!(matches!(err, ChopError::InvalidAnnotation { .. }));
+    assert_eq!(err.to_string(), "Invalid annotation @chop:private-region 0x2000..0x1000");
+    assert_eq!(err.span().map(|span| span.linecol_in(input)), Some((1, 7)));
+}
+
+#[test]
+fn comment_mentioning_annotation() {
+    // Only comments starting with the prefix are annotations
+    let input = "\
+(module
+    ;; see @chop:foo docs
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load ;; not @chop:nosplit
+    )
+    (memory 1)
+)";
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    assert_eq!(output.split_counts["transaction"], 1);
+}
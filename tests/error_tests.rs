@@ -0,0 +1,122 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{transform_wat_string, ChopError, SplitOptions};
+
+fn transform_err(input: &str) -> ChopError {
+    let mut output = Vec::new();
+    let err = transform_wat_string(input, &mut output, &SplitOptions::new(6)).unwrap_err();
+    assert!(output.is_empty());
+    err
+}
+
+#[test]
+fn unsupported_instruction() {
+    let input = "\
+(module
+    (func $grow (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 1
+        memory.grow
+    )
+    (memory 1)
+)";
+    let err = transform_err(input);
+    assert!(matches!(&err, ChopError::UnsupportedInstruction { instruction, .. } if instruction == "MemoryGrow"));
+    assert_eq!(err.function(), Some("grow"));
+    assert_eq!(err.span().map(|span| span.linecol_in(input)), Some((3, 8)));
+}
+
+#[test]
+fn unsupported_type() {
+    let err = transform_err("\
+(module
+    (func $vector (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local v128)
+        i32.const 0
+    )
+)");
+    assert!(matches!(&err, ChopError::UnsupportedType { ty, .. } if ty == "V128"));
+    assert_eq!(err.function(), Some("vector"));
+}
+
+#[test]
+fn malformed_stack() {
+    let input = "\
+(module
+    (func $underflow (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.add
+    )
+)";
+    let err = transform_err(input);
+    assert!(matches!(err, ChopError::MalformedStack { .. }));
+    assert_eq!(err.to_string(), "Unbalanced stack - input program is malformed in function $underflow");
+    assert_eq!(err.span().map(|span| span.linecol_in(input)), Some((2, 8)));
+}
+
+#[test]
+fn abi_overflow() {
+    let err = transform_err("\
+(module
+    (func $far (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i64.const 0
+        i32.load offset=4294967296
+    )
+    (memory i64 1)
+)");
+    assert!(matches!(err, ChopError::AbiOverflow { .. }));
+    assert_eq!(err.function(), Some("far"));
+}
+
+#[test]
+fn synthetic_address_overflow() {
+    // Globals past the first 8192 have no synthetic address left below 2^32
+    let globals = "    (global (mut i32) (i32.const 0))\n".repeat(8193);
+    let input = format!("\
+(module
+{globals}    (func $last (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        global.get 8192
+    )
+)");
+    let mut output = Vec::new();
+    let err = transform_wat_string(&input, &mut output, &SplitOptions::new(6).split_globals(true)).unwrap_err();
+    assert!(matches!(err, ChopError::AbiOverflow { .. }));
+    assert_eq!(err.function(), Some("last"));
+    assert_eq!(err.to_string(), "No synthetic address left for global 8192, which does not fit the microtransaction ABI in function $last");
+}
+
+#[test]
+fn diagnostic_points_at_instruction() {
+    let input = "\
+(module
+    (func $underflow (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 1
+        i32.add
+    )
+)";
+    let err = transform_err(input);
+    assert_eq!(
+        err.diagnostic("underflow.wat", input).to_string(),
+        "\
+Unbalanced stack - input program is malformed
+ --> underflow.wat:4:9
+  |
+4 |         i32.add
+  |         ^^^^^^^
+  = in function $underflow"
+    );
+}
+
+#[test]
+fn diagnostic_without_span() {
+    let input = "\
+(module
+    (func $f (param $tx i32) (param $utx i32) (param $state i32) (result i32) )
+)";
+    let err = transform_err(input);
+    assert_eq!(
+        err.diagnostic("empty.wat", input).to_string(),
+        "\
+Transformed module is invalid: type mismatch: expected i32 but nothing on stack
+ --> empty.wat
+  = in function $f"
+    );
+}
@@ -0,0 +1,238 @@
+use proptest::prelude::*;
+
+use chop_up::{AlwaysSplit, SkipSafe, SplitOptions, SplitPolicy, StaticAnalysis, Transaction, verify_wat_string};
+
+const TX: u32 = 1024;
+/// Every generated address lies within this many bytes of tx
+const TX_REGION: usize = 512;
+const UTX: u32 = 4096;
+const STATE: u32 = 4352;
+const STATE_SIZE: usize = 6;
+const MAX_DEPTH: u32 = 2;
+
+#[derive(Clone, Copy, Debug)]
+enum Ty {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl Ty {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ty::I32 => "i32",
+            Ty::I64 => "i64",
+            Ty::F32 => "f32",
+            Ty::F64 => "f64",
+        }
+    }
+
+    /// Indices of the declared locals of this type, following the three parameters
+    fn locals(self) -> &'static [u32] {
+        match self {
+            Ty::I32 => &[3, 4],
+            Ty::I64 => &[5, 6],
+            Ty::F32 => &[7],
+            Ty::F64 => &[8],
+        }
+    }
+
+    fn loads(self) -> &'static [&'static str] {
+        match self {
+            Ty::I32 => &["load", "load8_s", "load8_u", "load16_s", "load16_u"],
+            Ty::I64 => &["load", "load8_s", "load8_u", "load16_s", "load16_u", "load32_s", "load32_u"],
+            Ty::F32 | Ty::F64 => &["load"],
+        }
+    }
+
+    fn stores(self) -> &'static [&'static str] {
+        match self {
+            Ty::I32 => &["store", "store8", "store16"],
+            Ty::I64 => &["store", "store8", "store16", "store32"],
+            Ty::F32 | Ty::F64 => &["store"],
+        }
+    }
+}
+
+fn ty() -> impl Strategy<Value = Ty> {
+    prop_oneof![Just(Ty::I32), Just(Ty::I64), Just(Ty::F32), Just(Ty::F64)]
+}
+
+fn pick(options: &'static [&'static str]) -> impl Strategy<Value = &'static str> {
+    prop::sample::select(options)
+}
+
+fn constant(ty: Ty) -> BoxedStrategy<Vec<String>> {
+    match ty {
+        Ty::I32 => any::<i32>().prop_map(|value| vec![format!("i32.const {value}")]).boxed(),
+        Ty::I64 => any::<i64>().prop_map(|value| vec![format!("i64.const {value}")]).boxed(),
+        // Quarters are exactly representable, keeping the text and the bits in agreement
+        Ty::F32 | Ty::F64 => any::<i16>()
+            .prop_map(move |value| vec![format!("{}.const {}", ty.as_str(), f64::from(value) / 4.0)])
+            .boxed(),
+    }
+}
+
+fn local_get(ty: Ty) -> impl Strategy<Value = Vec<String>> {
+    prop::sample::select(ty.locals()).prop_map(|local| vec![format!("local.get {local}")])
+}
+
+/// An address within the tx region, possibly derived from loaded values
+fn address(depth: u32) -> BoxedStrategy<Vec<String>> {
+    let tx = Just(vec!["local.get $tx".to_string()]);
+    if depth == 0 {
+        return tx.boxed();
+    }
+    prop_oneof![
+        tx,
+        expression(Ty::I32, depth - 1).prop_map(|mut lines| {
+            lines.extend(["i32.const 248", "i32.and", "local.get $tx", "i32.add"].map(String::from));
+            lines
+        }),
+    ]
+    .boxed()
+}
+
+fn expression(ty: Ty, depth: u32) -> BoxedStrategy<Vec<String>> {
+    let leaf = prop_oneof![constant(ty), local_get(ty)];
+    if depth == 0 {
+        return leaf.boxed();
+    }
+    let load = (address(depth - 1), pick(ty.loads()), 0..8u32).prop_map(move |(mut lines, load, offset)| {
+        lines.push(format!("{}.{load} offset={offset}", ty.as_str()));
+        lines
+    });
+    match ty {
+        Ty::I32 | Ty::I64 => {
+            let binary = (
+                expression(ty, depth - 1),
+                expression(ty, depth - 1),
+                pick(&["add", "sub", "mul", "and", "or", "xor"]),
+            )
+                .prop_map(move |(mut lines, rhs, op)| {
+                    lines.extend(rhs);
+                    lines.push(format!("{}.{op}", ty.as_str()));
+                    lines
+                });
+            prop_oneof![leaf, load, binary].boxed()
+        }
+        Ty::F32 | Ty::F64 => prop_oneof![leaf, load].boxed(),
+    }
+}
+
+fn store(ty: Ty, depth: u32) -> impl Strategy<Value = Vec<String>> {
+    (address(depth), expression(ty, depth), pick(ty.stores()), 0..8u32).prop_map(
+        move |(mut lines, value, store, offset)| {
+            lines.extend(value);
+            lines.push(format!("{}.{store} offset={offset}", ty.as_str()));
+            lines
+        },
+    )
+}
+
+fn set_local(ty: Ty, depth: u32) -> impl Strategy<Value = Vec<String>> {
+    (expression(ty, depth), prop::sample::select(ty.locals()), any::<bool>()).prop_map(
+        |(mut lines, local, tee)| {
+            if tee {
+                lines.extend([format!("local.tee {local}"), "drop".into()]);
+            } else {
+                lines.push(format!("local.set {local}"));
+            }
+            lines
+        },
+    )
+}
+
+/// A stack neutral statement
+fn statement(depth: u32) -> BoxedStrategy<Vec<String>> {
+    let simple = ty().prop_flat_map(move |ty| prop_oneof![store(ty, depth), set_local(ty, depth)]);
+    if depth == 0 {
+        return simple.boxed();
+    }
+    let block = prop::collection::vec(statement(depth - 1), 0..4).prop_map(|statements| {
+        block_lines("block".into(), statements, Vec::default(), Vec::default())
+    });
+    let block_with_result = ty().prop_flat_map(move |ty| {
+        (
+            prop::collection::vec(statement(depth - 1), 0..4),
+            expression(ty, depth - 1),
+            prop::sample::select(ty.locals()),
+        )
+            .prop_map(move |(statements, result, local)| {
+                block_lines(
+                    format!("block (result {})", ty.as_str()),
+                    statements,
+                    result,
+                    vec![format!("local.set {local}")],
+                )
+            })
+    });
+    prop_oneof![2 => simple, 1 => block, 1 => block_with_result].boxed()
+}
+
+fn block_lines(opening: String, statements: Vec<Vec<String>>, result: Vec<String>, after: Vec<String>) -> Vec<String> {
+    let body = statements.into_iter().flatten().chain(result).map(|line| format!("    {line}"));
+    [opening].into_iter().chain(body).chain(["end".into()]).chain(after).collect()
+}
+
+/// A random well-typed transaction function in a module of its own
+fn transaction_module() -> impl Strategy<Value = String> {
+    prop::collection::vec(statement(MAX_DEPTH), 1..6).prop_map(|statements| {
+        let body = statements
+            .into_iter()
+            .flatten()
+            .chain(["i32.const 0".to_string()])
+            .map(|line| format!("        {line}\n"))
+            .collect::<String>();
+        format!("\
+(module
+    (type (func (param i32 i32 i32) (result i32)))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32 i32 i64 i64 f32 f64)
+{body}    )
+    (memory 1)
+)")
+    })
+}
+
+fn memory() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), TX_REGION).prop_map(|tx_region| {
+        let mut memory = vec![0; TX as usize];
+        memory.extend(tx_region);
+        memory
+    })
+}
+
+fn check_split(module: &str, memory: Vec<u8>, policy: impl SplitPolicy + 'static) -> Result<(), TestCaseError> {
+    let transaction = Transaction {
+        function: "transaction".into(),
+        tx: TX,
+        utx: UTX,
+        state: STATE,
+        memory,
+    };
+    let verification = verify_wat_string(module, &transaction, &SplitOptions::new(STATE_SIZE).policy(policy))
+        .map_err(|err| TestCaseError::fail(format!("{err:?}\n{module}")))?;
+    prop_assert!(verification.is_equivalent(), "{verification}\n{module}");
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn split_is_equivalent(module in transaction_module(), memory in memory()) {
+        check_split(&module, memory, AlwaysSplit)?;
+    }
+
+    #[test]
+    fn split_skip_safe_is_equivalent(module in transaction_module(), memory in memory()) {
+        check_split(&module, memory, SkipSafe)?;
+    }
+
+    #[test]
+    fn split_static_analysis_is_equivalent(module in transaction_module(), memory in memory()) {
+        check_split(&module, memory, StaticAnalysis)?;
+    }
+}
@@ -0,0 +1,129 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{write_graph, SplitOptions};
+
+#[test]
+fn graph_with_splits() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (block $done
+            local.get $tx
+            i32.load
+            br_if $done
+            i32.const 64
+            i32.load
+            drop
+        )
+        i32.const 128
+        i32.load
+    )
+    (memory 1)
+)";
+    let mut output = Vec::new();
+    write_graph(input, &SplitOptions::new(6), &mut output).unwrap();
+    // Blocks starting at a split access are filled, and every microtransaction points at the block it starts in
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+digraph {
+    node [shape=box fontname=\"monospace\"];
+    subgraph \"cluster_transfer\" {
+        label=\"$transfer\";
+        \"transfer@0\" [label=\"(block $done\\llocal.get $tx\\l\"];
+        \"transfer@2\" [label=\"i32.load\\lbr_if $done\\l\" style=filled fillcolor=\"#f4cccc\"];
+        \"transfer@4\" [label=\"i32.const 64\\l\"];
+        \"transfer@5\" [label=\"i32.load\\ldrop\\l\" style=filled fillcolor=\"#f4cccc\"];
+        \"transfer@7\" [label=\")\\li32.const 128\\l\"];
+        \"transfer@9\" [label=\"i32.load\\l\" style=filled fillcolor=\"#f4cccc\"];
+        \"transfer@exit\" [label=\"exit\" shape=oval];
+        \"transfer@0\" -> \"transfer@2\";
+        \"transfer@2\" -> \"transfer@7\";
+        \"transfer@2\" -> \"transfer@4\";
+        \"transfer@4\" -> \"transfer@5\";
+        \"transfer@5\" -> \"transfer@7\";
+        \"transfer@7\" -> \"transfer@9\";
+        \"transfer@9\" -> \"transfer@exit\";
+        \"utx transfer\" [label=\"$transfer\" shape=ellipse];
+        \"utx transfer\" -> \"transfer@0\" [style=dashed];
+        \"utx transfer_1\" [label=\"$transfer_1\" shape=ellipse];
+        \"utx transfer_1\" -> \"transfer@2\" [style=dashed];
+        \"utx transfer_2\" [label=\"$transfer_2\" shape=ellipse];
+        \"utx transfer_2\" -> \"transfer@9\" [style=dashed];
+        \"utx transfer_1_1\" [label=\"$transfer_1_1\" shape=ellipse];
+        \"utx transfer_1_1\" -> \"transfer@5\" [style=dashed];
+        \"utx transfer\" -> \"utx transfer_1\" [style=bold];
+        \"utx transfer\" -> \"utx transfer_2\" [style=bold];
+        \"utx transfer_1\" -> \"utx transfer_2\" [style=bold];
+        \"utx transfer_1\" -> \"utx transfer_1_1\" [style=bold];
+        \"utx transfer_1_1\" -> \"utx transfer_2\" [style=bold];
+    }
+}
+"
+    );
+}
+
+#[test]
+fn graph_with_shadowed_label() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (block $done
+            (block $done
+                local.get $tx
+                br_if $done
+                i32.const 64
+                i32.load
+                drop
+            )
+            local.get $tx
+            br_if $done
+            i32.const 128
+            i32.load
+            drop
+        )
+        i32.const 0
+    )
+    (memory 1)
+)";
+    let mut output = Vec::new();
+    write_graph(input, &SplitOptions::new(6), &mut output).unwrap();
+    // Each `br_if $done` continues at the end of the innermost block named $done enclosing it
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+digraph {
+    node [shape=box fontname=\"monospace\"];
+    subgraph \"cluster_transfer\" {
+        label=\"$transfer\";
+        \"transfer@0\" [label=\"(block $done\\l(block $done\\llocal.get $tx\\lbr_if $done\\l\"];
+        \"transfer@4\" [label=\"i32.const 64\\l\"];
+        \"transfer@5\" [label=\"i32.load\\ldrop\\l\" style=filled fillcolor=\"#f4cccc\"];
+        \"transfer@7\" [label=\")\\llocal.get $tx\\lbr_if $done\\l\"];
+        \"transfer@10\" [label=\"i32.const 128\\l\"];
+        \"transfer@11\" [label=\"i32.load\\ldrop\\l\" style=filled fillcolor=\"#f4cccc\"];
+        \"transfer@13\" [label=\")\\li32.const 0\\l\"];
+        \"transfer@exit\" [label=\"exit\" shape=oval];
+        \"transfer@0\" -> \"transfer@7\";
+        \"transfer@0\" -> \"transfer@4\";
+        \"transfer@4\" -> \"transfer@5\";
+        \"transfer@5\" -> \"transfer@7\";
+        \"transfer@7\" -> \"transfer@13\";
+        \"transfer@7\" -> \"transfer@10\";
+        \"transfer@10\" -> \"transfer@11\";
+        \"transfer@11\" -> \"transfer@13\";
+        \"transfer@13\" -> \"transfer@exit\";
+        \"utx transfer\" [label=\"$transfer\" shape=ellipse];
+        \"utx transfer\" -> \"transfer@0\" [style=dashed];
+        \"utx transfer_1\" [label=\"$transfer_1\" shape=ellipse];
+        \"utx transfer_1\" -> \"transfer@5\" [style=dashed];
+        \"utx transfer_2\" [label=\"$transfer_2\" shape=ellipse];
+        \"utx transfer_2\" -> \"transfer@11\" [style=dashed];
+        \"utx transfer\" -> \"utx transfer_1\" [style=bold];
+        \"utx transfer\" -> \"utx transfer_2\" [style=bold];
+        \"utx transfer_1\" -> \"utx transfer_2\" [style=bold];
+    }
+}
+"
+    );
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use pretty_assertions::assert_eq;
+
+use chop_up::{
+    run_split, split_wat_string, transform_wat_string, verify_wat_string, AbiLayout, ExplainLevel, ModuleFormat,
+    SplitOptions, Transaction,
+};
+
+const INPUT: &str = "\
+(module
+    (func $bid (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+        drop
+        i32.const 0
+    )
+    (func $noop (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+    )
+    (memory 1)
+)";
+
+#[test]
+fn function_filter() {
+    let options = SplitOptions::new(6).only_functions(["bid", "noop"]).skip_functions(["noop", "missing"]);
+    let output = split_wat_string(INPUT, &options).unwrap();
+    assert_eq!(output.split_counts, BTreeMap::from([("bid".to_string(), 1)]));
+    assert!(output.module.contains("\
+    (func $noop (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+    )"));
+    assert_eq!(output.warnings, vec!["No function named $missing to select for splitting"]);
+}
+
+#[test]
+fn abi_layout() {
+    let abi = AbiLayout {
+        addrs_offset: 4,
+        log2lens_offset: 32,
+        naddr_offset: 39,
+        size: 40,
+        max_addrs: 7,
+    };
+    let options = SplitOptions::new(6).abi(abi);
+    let output = split_wat_string(INPUT, &options).unwrap();
+    assert!(output.module.contains("i32.store offset=4\n"));
+    assert!(output.module.contains("i32.store8 offset=39\n"));
+    assert!(output.module.contains("i32.load offset=4\n"));
+
+    let transaction = Transaction {
+        function: "bid".into(),
+        tx: 1024,
+        utx: 3000,
+        state: 3100,
+        memory: Vec::default(),
+    };
+    let verification = verify_wat_string(INPUT, &transaction, &options).unwrap();
+    assert_eq!(verification.split_accesses, vec![1024]);
+    assert!(verification.is_equivalent());
+}
+
+#[test]
+fn invalid_abi_layout() {
+    let error = |abi: AbiLayout| split_wat_string(INPUT, &SplitOptions::new(6).abi(abi)).unwrap_err().to_string();
+    assert_eq!(
+        error(AbiLayout { max_addrs: 0, ..AbiLayout::default() }),
+        "A capacity of 0 addresses, which does not fit the microtransaction ABI"
+    );
+    assert_eq!(
+        error(AbiLayout { max_addrs: 256, ..AbiLayout::default() }),
+        "A capacity of 256 addresses, which does not fit the microtransaction ABI"
+    );
+    assert_eq!(
+        error(AbiLayout { size: 35, ..AbiLayout::default() }),
+        "Field naddr at 35..36 of a 35 byte utx, which does not fit the microtransaction ABI"
+    );
+    assert_eq!(
+        error(AbiLayout { log2lens_offset: 24, ..AbiLayout::default() }),
+        "Field log2lens at 24..31 overlapping addrs at 0..28, which does not fit the microtransaction ABI"
+    );
+    assert_eq!(
+        error(AbiLayout { naddr_offset: 34, ..AbiLayout::default() }),
+        "Field naddr at 34..35 overlapping log2lens at 28..35, which does not fit the microtransaction ABI"
+    );
+    assert!(AbiLayout::default().validate().is_ok());
+}
+
+#[test]
+fn explain_source() {
+    let options = SplitOptions::new(6).explain(ExplainLevel::Source);
+    let output = split_wat_string(INPUT, &options).unwrap();
+    assert!(output.module.contains("local.get $tx                 ;;line 3\n"));
+    assert!(output.module.contains("local.set $memory_address     ;;Save address for load\n"));
+    assert!(output.module.contains("drop                          ;;line 5\n"));
+}
+
+#[test]
+fn binary_format() {
+    let mut output = Vec::new();
+    transform_wat_string(INPUT, &mut output, &SplitOptions::new(6).format(ModuleFormat::Binary)).unwrap();
+    assert!(output.starts_with(b"\0asm"));
+}
+
+#[test]
+fn split_outputs() {
+    let directory = std::env::temp_dir().join(format!("chop_up_split_outputs_{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let input_path = directory.join("input.wat");
+    let source_map_path = directory.join("input.map.json");
+    std::fs::write(&input_path, INPUT).unwrap();
+
+    // Outputs besides the module are options like any other
+    let options = SplitOptions::new(6).source_map_path(source_map_path.to_str().unwrap()).stats(true);
+    let mut output = Vec::new();
+    run_split(input_path.to_str().unwrap(), &options, &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), split_wat_string(INPUT, &options).unwrap().module);
+    let source_map = std::fs::read_to_string(&source_map_path).unwrap();
+    assert!(source_map.contains("\"bid_1\""));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
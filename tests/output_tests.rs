@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+use pretty_assertions::assert_eq;
+
+use chop_up::{split_wat_string, Microtransaction, SplitOptions};
+
+#[test]
+fn transform_output() {
+    let input = "\
+(module
+    (import \"env\" \"log\" (func $log (param i32)))
+    (func $bid (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        i32.const 1
+        local.get $tx
+        i32.load
+        i32.store
+        i32.const 0
+    )
+    (func $noop (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
+    )
+    (memory 1)
+)";
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    let microtransaction = |name: &str, table_index, function: &str, saved_state, successors: &[usize]| Microtransaction {
+        name: name.into(),
+        table_index,
+        function: function.into(),
+        saved_state,
+        successors: successors.to_vec(),
+    };
+    assert_eq!(
+        output.microtransactions,
+        vec![
+            microtransaction("bid", 1, "bid", 0, &[2]),
+            // The constant below the load is saved along with the i64 local
+            microtransaction("bid_1", 2, "bid", 20, &[4]),
+            // Table indices follow the order the functions are emitted in
+            microtransaction("noop", 3, "noop", 0, &[]),
+            microtransaction("bid_1_1", 4, "bid", 16, &[]),
+        ]
+    );
+    assert_eq!(
+        output.split_counts,
+        BTreeMap::from([("bid".to_string(), 2), ("noop".to_string(), 0)])
+    );
+    assert_eq!(output.state_usage, 20);
+    assert_eq!(output.max_chain_length("bid"), 3);
+    assert_eq!(output.warnings, vec!["Import env.log is not carried over to the transformed module"]);
+    assert!(output.module.starts_with("(module\n    (func $bid"));
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use pretty_assertions::assert_eq;
+
+use chop_up::{
+    split_wat_string, verify_wat_string, ChopError, MemoryInstructionType, SplitDecision, SplitOptions,
+    SplitPolicy, SplitSite, StaticAnalysis, Transaction,
+};
+
+const INPUT: &str = "\
+(module
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 2048
+        i32.load
+        i32.load
+        i32.const 2052
+        i32.const 1
+        i32.store
+        drop
+        i32.const 0
+    )
+    (memory 1)
+)";
+
+fn transaction() -> Transaction {
+    Transaction {
+        function: "transaction".into(),
+        tx: 1024,
+        utx: 3000,
+        state: 3100,
+        memory: Vec::default(),
+    }
+}
+
+#[test]
+fn static_analysis_batches_constant_addresses() {
+    let options = SplitOptions::new(6).policy(StaticAnalysis);
+    let output = split_wat_string(INPUT, &options).unwrap();
+    // The constant load has no split before it so it is split on its own,
+    // and the constant store is declared along with the split at the dynamic load
+    assert_eq!(output.split_counts, BTreeMap::from([("transaction".to_string(), 2)]));
+    assert!(output.module.contains("\
+        local.get $utx
+        i32.const 2052
+        i32.store offset=4
+        local.get $utx
+        i32.const 2
+        i32.store8 offset=35"));
+
+    let verification = verify_wat_string(INPUT, &transaction(), &options).unwrap();
+    assert_eq!(verification.original_accesses, vec![2048, 0, 2052]);
+    assert_eq!(verification.split_accesses, vec![2048, 0, 2052]);
+    assert!(verification.is_equivalent());
+}
+
+/// Splits at stores only
+#[derive(Debug)]
+struct StoresOnly;
+
+impl SplitPolicy for StoresOnly {
+    fn decide(&self, site: &SplitSite) -> Result<SplitDecision, ChopError> {
+        Ok(match site.access {
+            MemoryInstructionType::Load { .. } => SplitDecision::NoSplit,
+            MemoryInstructionType::Store { .. } => SplitDecision::Split,
+        })
+    }
+}
+
+#[test]
+fn custom_policy() {
+    let options = SplitOptions::new(6).policy(StoresOnly);
+    let output = split_wat_string(INPUT, &options).unwrap();
+    assert_eq!(output.split_counts, BTreeMap::from([("transaction".to_string(), 1)]));
+    assert_eq!(output.source_map.microtransactions[1].culprit.as_ref().unwrap().instruction, "i32.store");
+
+    let verification = verify_wat_string(INPUT, &transaction(), &options).unwrap();
+    assert_eq!(verification.split_accesses, vec![2052]);
+    assert!(verification.is_equivalent());
+}
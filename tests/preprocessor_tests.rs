@@ -1,3 +1,5 @@
+use chop_up::{transform_wat_string, ChopError, SplitOptions, ValidationError};
+
 mod utils;
 
 #[test]
@@ -6,14 +8,16 @@ fn joined_lines() {
         "\
 (module
     (func $f_1 (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
         i32.const 1
         drop)
 
     (func $f_2 (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
         i32.const 1
         drop )
 
-    (func $f_3 (param $tx i32) (param $utx i32) (param $state i32) (result i32) )
+    (func $f_3 (param $tx i32) (param $utx i32) (param $state i32) (result i32) i32.const 0)
 )",
         "\
 (module
@@ -23,6 +27,7 @@ fn joined_lines() {
         (local $i64_local i64)
         (local $f32_local f32)
         (local $f64_local f64)
+        i32.const 0
         i32.const 1
         drop
     )
@@ -32,6 +37,7 @@ fn joined_lines() {
         (local $i64_local i64)
         (local $f32_local f32)
         (local $f64_local f64)
+        i32.const 0
         i32.const 1
         drop
     )
@@ -41,6 +47,7 @@ fn joined_lines() {
         (local $i64_local i64)
         (local $f32_local f32)
         (local $f64_local f64)
+        i32.const 0
     )
     (table 4 funcref)
     (elem (i32.const 1) func $f_1 $f_2 $f_3)
@@ -69,6 +76,7 @@ fn single_assignment_conversion() {
         local.get 2
         i32.add
         drop
+        i32.const 0
     )
     (func $with_locals (param $tx i32) (param $utx i32) (param $state i32) (result i32)
         (local i32 i32 i64)
@@ -77,6 +85,7 @@ fn single_assignment_conversion() {
         local.set 2
         local.get 2
         drop
+        i32.const 0
     )
 )",
         "\
@@ -88,8 +97,12 @@ fn single_assignment_conversion() {
         (local $i64_local i64)
         (local $f32_local f32)
         (local $f64_local f64)
-        i32.const 1
         local.get 1
+        local.set 3
+        local.get 2
+        local.set 4
+        i32.const 1
+        local.get 3
         i32.add
         local.set 3
         i32.const 1
@@ -101,6 +114,7 @@ fn single_assignment_conversion() {
         local.get 4
         i32.add
         drop
+        i32.const 0
     )
     (func $with_locals (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
         (local i32 i32 i64 i32)
@@ -109,11 +123,14 @@ fn single_assignment_conversion() {
         (local $i64_local i64)
         (local $f32_local f32)
         (local $f64_local f64)
+        local.get 2
+        local.set 6
         local.get 3
         local.tee 3
         local.set 6
         local.get 6
         drop
+        i32.const 0
     )
     (table 3 funcref)
     (elem (i32.const 1) func $without_locals $with_locals)
@@ -122,3 +139,171 @@ fn single_assignment_conversion() {
 )"
     );
 }
+
+#[test]
+fn named_locals() {
+    utils::test_transform(
+        "\
+(module
+    (func $named_locals (param $tx i32) (param $u i32) (param $state i32) (result i32)
+        (local $sum i64)
+        local.get $tx
+        i64.load
+        local.set $sum
+        local.get $u
+        local.get $sum
+        i64.store
+        i32.const 0
+    )
+)",
+        "\
+(module
+    (func $named_locals (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $tx
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        local.get $state
+        local.get 3
+        i64.store offset=14
+        i32.const 2
+    )
+    (func $named_locals_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $state
+        i64.load offset=14
+        local.set 3
+        local.get $utx
+        i32.load
+        i64.load
+        local.set 3
+        local.get 1
+        local.get 3
+        local.set $i64_local
+        local.set $memory_address
+        local.get $state
+        local.get $i64_local
+        i64.store offset=6
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        local.get $state
+        local.get 3
+        i64.store offset=14
+        i32.const 3
+    )
+    (func $named_locals_1_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $state
+        i64.load offset=14
+        local.set 3
+        local.get $utx
+        i32.load
+        local.get $state
+        i64.load offset=6
+        i64.store
+        i32.const 0
+    )
+    (table 4 funcref)
+    (elem (i32.const 1) func $named_locals $named_locals_1 $named_locals_1_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn named_single_assignment_conversion() {
+    utils::test_transform(
+        "\
+(module
+    (func $named_params (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32 i32 i32 i32 i32 i32 i32 i32)
+        local.get $tx
+        i32.const 4
+        i32.add
+        local.set $tx ;; advance tx
+        local.get 0
+        local.get $state
+        i32.add
+        local.tee 2
+        drop
+        i32.const 0
+    )
+)",
+        "\
+(module
+    (func $named_params (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32 i32 i32 i32 i32 i32 i32 i32 i32 i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get 0
+        local.set 11
+        local.get 2
+        local.set 12
+        local.get 11
+        i32.const 4
+        i32.add
+        local.set 11 ;; advance tx
+        local.get 11
+        local.get 12
+        i32.add
+        local.tee 12
+        drop
+        i32.const 0
+    )
+    (table 2 funcref)
+    (elem (i32.const 1) func $named_params)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn empty_function_is_rejected() {
+    // The joined closing paren is handled, but an empty body can not produce the next microtransaction
+    let mut output = Vec::new();
+    let err = transform_wat_string(
+        "\
+(module
+    (func $f_3 (param $tx i32) (param $utx i32) (param $state i32) (result i32) )
+)",
+        &mut output,
+        &SplitOptions::new(6),
+    )
+    .unwrap_err();
+    assert!(matches!(&err, ChopError::Invalid(ValidationError { .. })));
+    assert_eq!(err.function(), Some("f_3"));
+    assert!(output.is_empty());
+}
@@ -0,0 +1,252 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{
+    parse_address_range, split_wat_string, verify_wat_string, ShadowStack, SplitOptions, Transaction,
+};
+
+const SHADOW_STACK: &str = "\
+(module
+    (global (mut i32) (i32.const 2048))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32)
+        global.get 0
+        i32.const 16
+        i32.sub
+        local.tee 3
+        global.set 0
+        local.get 3
+        local.get $tx
+        i32.load
+        i32.store offset=12
+        local.get 3
+        i32.load offset=12
+        local.get 3
+        i32.const 16
+        i32.add
+        global.set 0
+    )
+    (memory 1)
+)";
+
+fn transaction() -> Transaction {
+    Transaction {
+        function: "transaction".into(),
+        tx: 1024,
+        utx: 3000,
+        state: 3100,
+        memory: Vec::default(),
+    }
+}
+
+#[test]
+fn private_region() {
+    let input = "\
+(module
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $index i32)
+        local.get $tx
+        i32.load8_u
+        i32.const 2
+        i32.shl
+        local.set $index
+        local.get $index
+        i32.const 4096
+        i32.add
+        i32.load
+        local.get $index
+        i32.const 4096
+        i32.add
+        i32.load offset=512
+        i32.add
+    )
+    (memory 1)
+)";
+    // Only the byte read through $tx and the load reaching past the end of the region are split
+    let options = SplitOptions::new(6).private_region(parse_address_range("0x1000..0x1400").unwrap());
+    let output = split_wat_string(input, &options).unwrap();
+    assert_eq!(output.split_counts["transaction"], 2);
+    assert_eq!(output.unsplit_accesses, 1);
+
+    let mut transaction = transaction();
+    transaction.memory = vec![0; 1025];
+    transaction.memory[1024] = 3;
+    let verification = verify_wat_string(input, &transaction, &options).unwrap();
+    assert_eq!(verification.original_accesses, vec![1024, 4108, 4620]);
+    assert_eq!(verification.split_accesses, vec![1024, 4620]);
+    assert!(verification.is_equivalent());
+}
+
+#[test]
+fn private_global() {
+    let input = "\
+(module
+    (global $__stack_pointer (mut i32) (i32.const 2048))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $frame i32)
+        global.get $__stack_pointer
+        i32.const 16
+        i32.sub
+        local.tee $frame
+        global.set $__stack_pointer
+        local.get $frame
+        local.get $tx
+        i32.load
+        i32.store offset=12
+        local.get $frame
+        i32.load offset=12
+        local.get $frame
+        i32.const 16
+        i32.add
+        global.set $__stack_pointer
+    )
+    (memory 1)
+)";
+    let options = SplitOptions::new(6).split_globals(true).private_global("__stack_pointer");
+    let output = split_wat_string(input, &options).unwrap();
+    assert_eq!(output.split_counts["transaction"], 1);
+    assert_eq!(output.unsplit_accesses, 2);
+
+    let verification = verify_wat_string(input, &transaction(), &options).unwrap();
+    assert_eq!(verification.split_accesses, vec![1024]);
+    assert!(verification.is_equivalent());
+}
+
+#[test]
+fn far_from_private_global() {
+    let input = "\
+(module
+    (global $__stack_pointer (mut i32) (i32.const 2048))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        global.get $__stack_pointer
+        i32.const 0x7fff0000
+        i32.add
+        i32.load
+        global.get $__stack_pointer
+        i32.const 0
+        i32.and
+        i32.load
+        i32.add
+        global.get $__stack_pointer
+        i32.load offset=0x20000
+        i32.add
+        global.get $__stack_pointer
+        i32.const 16
+        i32.sub
+        i32.load
+        i32.add
+    )
+    (memory 1)
+)";
+    // Only the load 16 bytes below the stack pointer stays private,
+    // a large offset, a mask or a large static offset may reach shared memory
+    let options = SplitOptions::new(6).private_global("__stack_pointer");
+    let output = split_wat_string(input, &options).unwrap();
+    assert_eq!(output.split_counts["transaction"], 3);
+    assert_eq!(output.unsplit_accesses, 1);
+}
+
+#[test]
+fn unknown_private_global() {
+    let input = "\
+(module
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
+    )
+)";
+    let output = split_wat_string(input, &SplitOptions::new(6).private_global("__stack_pointer")).unwrap();
+    assert_eq!(output.warnings, vec!["No global named $__stack_pointer to treat as private"]);
+}
+
+#[test]
+fn shadow_stack() {
+    // The stack pointer is recognised by the prologue moving it, and saved right after the store value
+    let output = split_wat_string(SHADOW_STACK, &SplitOptions::new(6).shadow_stack(ShadowStack::Detect)).unwrap();
+    assert_eq!(
+        output.module.trim(),
+        "\
+(module
+    (func $transaction (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        global.get 0
+        i32.const 16
+        i32.sub
+        local.tee 3
+        global.set 0
+        local.get 3
+        local.get $tx
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        local.set $i32_local
+        local.get $state
+        local.get $i32_local
+        i32.store offset=18
+        local.get $state
+        local.get 3
+        i32.store offset=22
+        local.get $state
+        global.get 0
+        i32.store offset=14
+        i32.const 2
+    )
+    (func $transaction_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $state
+        i32.load offset=22
+        local.set 3
+        local.get $state
+        i32.load offset=14
+        global.set 0
+        local.get $state
+        i32.load offset=18
+        local.get $utx
+        i32.load
+        i32.load
+        i32.store offset=12
+        local.get 3
+        i32.load offset=12
+        local.get 3
+        i32.const 16
+        i32.add
+        global.set 0
+    )
+    (global (mut i32) (i32.const 2048))
+    (table 3 funcref)
+    (elem (i32.const 1) func $transaction $transaction_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)"
+    );
+}
+
+#[test]
+fn shadow_stack_accesses_are_private() {
+    let options = SplitOptions::new(6).split_globals(true).shadow_stack(ShadowStack::Detect);
+    let output = split_wat_string(SHADOW_STACK, &options).unwrap();
+    assert_eq!(output.split_counts["transaction"], 1);
+    assert_eq!(output.unsplit_accesses, 2);
+
+    let verification = verify_wat_string(SHADOW_STACK, &transaction(), &options).unwrap();
+    assert_eq!(verification.split_accesses, vec![1024]);
+    assert!(verification.is_equivalent());
+
+    // Detection is opt-in
+    let output = split_wat_string(SHADOW_STACK, &SplitOptions::new(6)).unwrap();
+    assert_eq!(output.split_counts["transaction"], 3);
+}
@@ -0,0 +1,85 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{trace_wat_string, transform_wat_string, SplitOptions, Utx};
+
+const TRANSACTION: &str = "\
+(module
+    (type (func (param i32 i32 i32) (result i32)))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+        i32.load
+        drop
+        local.get $tx
+        i32.const 7
+        i32.store offset=4
+        i32.const 0
+    )
+    (func $__enter (param i32) (result i32)
+        i32.const 0
+        local.get 0
+        i32.store offset=1024
+        i32.const 1
+    )
+    (func $__step (param i32) (result i32)
+        i32.const 1024
+        i32.const 1056
+        i32.const 1100
+        local.get 0
+        call_indirect (type 0)
+    )
+    (func $__get_utx_addrs (param i32) (result i32)
+        local.get 0
+        i32.const 2
+        i32.shl
+        i32.load offset=1056
+    )
+    (func $__get_utx_log2lens (param i32) (result i32)
+        local.get 0
+        i32.load8_u offset=1084
+    )
+    (func $__get_utx_naddr (result i32)
+        i32.const 0
+        i32.load8_u offset=1091
+    )
+    (export \"__enter\" (func $__enter))
+    (export \"__step\" (func $__step))
+    (export \"__get_utx_addrs\" (func $__get_utx_addrs))
+    (export \"__get_utx_log2lens\" (func $__get_utx_log2lens))
+    (export \"__get_utx_naddr\" (func $__get_utx_naddr))
+)";
+
+fn split(input: &str) -> String {
+    let mut output_vec: Vec<u8> = Vec::new();
+    transform_wat_string(input, &mut output_vec, &SplitOptions::new(6)).unwrap();
+    String::from_utf8(output_vec).unwrap()
+}
+
+fn utx(addrs: &[u64]) -> Utx {
+    Utx {
+        naddr: addrs.len() as u8,
+        addrs: addrs.to_vec(),
+        log2lens: vec![0; addrs.len()],
+    }
+}
+
+#[test]
+fn trace_split_transaction() {
+    let trace = trace_wat_string(&split(TRANSACTION), &[2048]).unwrap();
+    assert_eq!(
+        trace.utxs,
+        vec![utx(&[]), utx(&[1024]), utx(&[2048]), utx(&[1028])]
+    );
+}
+
+#[test]
+fn trace_unsplit_transaction() {
+    // Without splitting the transaction runs to completion in a single step
+    let unsplit = TRANSACTION.replacen(
+        "(export",
+        "(table 2 funcref)\n    (elem (i32.const 1) func $transaction)\n    (memory 1)\n    (export",
+        1,
+    );
+    let trace = trace_wat_string(&unsplit, &[2048]).unwrap();
+    assert_eq!(trace.utxs, vec![utx(&[])]);
+}
@@ -0,0 +1,49 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{source_map_wat_string, Culprit, MicrotransactionMapping, SplitOptions};
+
+#[test]
+fn maps_microtransactions_to_original_instructions() {
+    let input = "\
+(module
+    (func $bid (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+        drop
+        block
+            local.get $tx
+            i32.const 5
+            i32.store offset=4
+            i32.const 1
+            drop
+        end
+        local.get $tx
+        i32.const 7
+        i32.store
+        i32.const 0
+    )
+    (memory 1)
+)";
+    let source_map = source_map_wat_string(input, &mut Vec::new(), &SplitOptions::new(6)).unwrap();
+    let mapping = |name: &str, table_index, instructions: &[(usize, usize)], culprit: Option<(&str, usize)>| MicrotransactionMapping {
+        name: name.into(),
+        table_index,
+        function: "bid".into(),
+        instructions: instructions.iter().map(|&(start, end)| start..end).collect(),
+        culprit: culprit.map(|(instruction, line)| Culprit {
+            instruction: instruction.into(),
+            line,
+        }),
+    };
+    assert_eq!(
+        source_map.microtransactions,
+        vec![
+            mapping("bid", 1, &[(0, 2)], None),
+            // The split inside of the block continues at the end of the block
+            mapping("bid_1", 2, &[(1, 7), (9, 13)], Some(("i32.load", 4))),
+            mapping("bid_1_1", 3, &[(6, 13)], Some(("i32.store offset=4", 9))),
+            // Both preceding microtransactions continue with the final store
+            mapping("bid_1_2", 4, &[(12, 14)], Some(("i32.store", 15))),
+        ]
+    );
+}
@@ -0,0 +1,70 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{split_stats, split_wat_string, FunctionStats, SplitOptions, SplitStats};
+
+#[test]
+fn split_stats_of_module() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.load
+        i32.const 64
+        i32.load
+        i32.add
+    )
+    (func $__helper (param i32) (result i32)
+        local.get 0
+    )
+    (memory 1)
+)";
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    let stats = split_stats(input, &output).unwrap();
+    assert_eq!(
+        stats,
+        SplitStats {
+            original_size: 107,
+            split_size: 476,
+            table_entries: 3,
+            functions: vec![FunctionStats {
+                name: "transfer".into(),
+                original_instructions: 5,
+                microtransactions: vec![
+                    ("transfer".into(), 11),
+                    ("transfer_1".into(), 18),
+                    ("transfer_1_1".into(), 6),
+                ],
+                // Saving and restoring the value loaded first, left on the stack across the second split
+                added_instructions: 6,
+            }],
+        }
+    );
+    assert_eq!(stats.blowup(), Some(476.0 / 107.0));
+}
+
+#[test]
+fn split_stats_of_store() {
+    let input = "\
+(module
+    (func $transfer (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.const 1
+        i32.store
+        i32.const 0
+    )
+    (memory 1)
+)";
+    let output = split_wat_string(input, &SplitOptions::new(6)).unwrap();
+    let stats = split_stats(input, &output).unwrap();
+    // Saving the stored value to state and loading it back
+    assert_eq!(stats.functions[0].added_instructions, 5);
+}
+
+#[test]
+fn blowup_of_empty_module() {
+    let stats = SplitStats::default();
+    assert_eq!(stats.blowup(), None);
+    let mut written = Vec::default();
+    stats.write(&mut written).unwrap();
+    assert_eq!(String::from_utf8(written).unwrap(), "Module size = 0 -> 0 bytes\nTable entries = 0\n");
+}
@@ -1,3 +1,5 @@
+use chop_up::SplitOptions;
+
 mod utils;
 #[test]
 fn load() {
@@ -599,7 +601,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 2
+            i32.const 3
             return
         )
         i32.const 0
@@ -726,7 +728,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 3
+            i32.const 4
             return
         )
         i32.const 0
@@ -779,7 +781,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 4
+            i32.const 5
             return
         )
         i32.const 0
@@ -839,7 +841,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 5
+            i32.const 6
             return
         )
         i32.const 0
@@ -912,7 +914,7 @@ fn transaction() {
                 local.get $state
                 local.get 6
                 i32.store offset=26
-                i32.const 6
+                i32.const 7
                 return
             )
             local.get 3
@@ -942,7 +944,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 7
+            i32.const 8
             return
         )
         i32.const 0
@@ -1005,7 +1007,7 @@ fn transaction() {
                 local.get $state
                 local.get 6
                 i32.store offset=26
-                i32.const 8
+                i32.const 9
                 return
             )
             local.get 3
@@ -1035,7 +1037,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 7
+            i32.const 8
             return
         )
         i32.const 0
@@ -1099,7 +1101,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=30
-            i32.const 9
+            i32.const 10
             return
         )
         i32.const 0
@@ -1160,7 +1162,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 7
+            i32.const 8
             return
         )
         i32.const 0
@@ -1223,7 +1225,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 10
+            i32.const 11
             return
         )
         i32.const 0
@@ -1280,7 +1282,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=30
-            i32.const 11
+            i32.const 12
             return
         )
         i32.const 0
@@ -1337,7 +1339,7 @@ fn transaction() {
             local.get $state
             local.get 6
             i32.store offset=26
-            i32.const 12
+            i32.const 13
             return
         )
         i32.const 0
@@ -1375,6 +1377,7 @@ fn transaction() {
     (type (;2;) (func (param i32) (result i32)))
     (type (;3;) (func (result i32)))
     (type (;4;) (func (param i32) (result i64)))
+    (table 14 funcref)
     (global $__stack_pointer (mut i32) (i32.const 146752))
     (global (;1;) i32 (i32.const 1024))
     (global (;2;) i32 (i32.const 81216))
@@ -1406,10 +1409,453 @@ fn transaction() {
     (export \"__heap_end\" (global 7))
     (export \"__memory_base\" (global 8))
     (export \"__table_base\" (global 9))
-    (table 13 funcref)
-    (elem (i32.const 1) func $enter $enter_1 $enter_1_1 $enter_1_1_1 $enter_1_1_1_1 $enter_1_1_1_1_1 $enter_1_1_1_1_2 $enter_1_1_1_1_1_1 $enter_1_1_1_1_2_1 $enter_1_1_1_1_2_1_1 $enter_1_1_1_1_2_1_1_1 $enter_1_1_1_1_2_1_1_1_1)
+    (elem (;0;) (i32.const 1) func $enter)
+    (elem (i32.const 2) func $enter $enter_1 $enter_1_1 $enter_1_1_1 $enter_1_1_1_1 $enter_1_1_1_1_1 $enter_1_1_1_1_2 $enter_1_1_1_1_1_1 $enter_1_1_1_1_2_1 $enter_1_1_1_1_2_1_1 $enter_1_1_1_1_2_1_1_1 $enter_1_1_1_1_2_1_1_1_1)
     (memory 10)
     (type $utx_f (func (param i32 i32 i32) (result i32)))
 )"
     );
 }
+
+#[test]
+fn mutable_global() {
+    utils::test_transform_with_opts(
+        "\
+(module
+    (func $global (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        global.get $counter
+        i32.const 1
+        i32.add
+        global.set $counter
+        global.get $limit
+        drop
+        i32.const 0
+    )
+    (global $limit i32 (i32.const 10))
+    (global $counter (mut i32) (i32.const 0))
+)",
+        "\
+(module
+    (func $global (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.const 4294901768
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 2
+    )
+    (func $global_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        global.get $counter
+        i32.const 1
+        i32.add
+        local.get $utx
+        i32.const 4294901768
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        local.set $i32_local
+        local.get $state
+        local.get $i32_local
+        i32.store offset=14
+        i32.const 3
+    )
+    (func $global_1_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $state
+        i32.load offset=14
+        global.set $counter
+        global.get $limit
+        drop
+        i32.const 0
+    )
+    (global $limit i32 (i32.const 10))
+    (global $counter (mut i32) (i32.const 0))
+    (table 4 funcref)
+    (elem (i32.const 1) func $global $global_1 $global_1_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+        SplitOptions::new(6).split_globals(true),
+    );
+}
+
+#[test]
+fn imported_global() {
+    // The import is carried over, so numeric indices of the globals after it stay correct
+    utils::test_transform_with_opts(
+        "\
+(module
+    (import \"env\" \"g\" (global i32))
+    (global (mut i32) (i32.const 64))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        global.get 1
+        i32.load
+        global.get 0
+        i32.add
+    )
+    (memory 1)
+)",
+        "\
+(module
+    (import \"env\" \"g\" (global i32))
+    (func $transaction (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.const 4294901768
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 2
+    )
+    (func $transaction_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        global.get 1
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 3
+    )
+    (func $transaction_1_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.load
+        i32.load
+        global.get 0
+        i32.add
+    )
+    (global (mut i32) (i32.const 64))
+    (table 4 funcref)
+    (elem (i32.const 1) func $transaction $transaction_1 $transaction_1_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+        SplitOptions::new(6).split_globals(true),
+    );
+}
+
+#[test]
+fn call_indirect() {
+    utils::test_transform(
+        "\
+(module
+    (type $get_f (func (param i32) (result i32)))
+    (func $call (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.const 1
+        call_indirect (type $get_f)
+        i32.load
+        drop
+        i32.const 0
+    )
+    (func $__get (type $get_f) (param i32) (result i32)
+        local.get 0
+    )
+    (table 2 funcref)
+    (elem (i32.const 1) func $__get)
+)",
+        "\
+(module
+    (func $call (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $tx
+        i32.const 1
+        call_indirect (type $get_f)
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 3
+    )
+    (func $__get (type $get_f) (param i32) (result i32)
+        local.get 0
+    )
+    (func $call_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.load
+        i32.load
+        drop
+        i32.const 0
+    )
+    (type $get_f (func (param i32) (result i32)))
+    (table 4 funcref)
+    (elem (i32.const 1) func $__get)
+    (elem (i32.const 2) func $call $call_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn multiple_tables() {
+    // The first table grows in its place, so the second one keeps its index
+    utils::test_transform(
+        "\
+(module
+    (type $get_f (func (param i32) (result i32)))
+    (table $t0 1 funcref)
+    (table $t1 5 funcref)
+    (elem (table $t0) (i32.const 0) func $__get)
+    (elem (table $t1) (i32.const 4) func $__get)
+    (func $call (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        i32.const 4
+        call_indirect $t1 (type $get_f)
+        i32.load
+    )
+    (func $__get (type $get_f) (param i32) (result i32)
+        local.get 0
+    )
+    (memory 1)
+)",
+        "\
+(module
+    (func $call (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $tx
+        i32.const 4
+        call_indirect $t1 (type $get_f)
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 2
+    )
+    (func $__get (type $get_f) (param i32) (result i32)
+        local.get 0
+    )
+    (func $call_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.load
+        i32.load
+    )
+    (type $get_f (func (param i32) (result i32)))
+    (table $t0 3 funcref)
+    (table $t1 5 funcref)
+    (elem (table $t0) (i32.const 0) func $__get)
+    (elem (table $t1) (i32.const 4) func $__get)
+    (elem (i32.const 1) func $call $call_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn multi_value_block() {
+    utils::test_transform(
+        "\
+(module
+    (func $multi_value (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 8
+        i32.const 4
+        (block $b (param i32) (result i32 i32)
+            i32.load
+            i32.const 1
+        )
+        i32.add
+        i32.add
+        drop
+        i32.const 0
+    )
+)",
+        "\
+(module
+    (func $multi_value (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        i32.const 8
+        i32.const 4
+        local.set $i32_local
+        local.get $state
+        local.get $i32_local
+        i32.store offset=18
+        local.set $i32_local
+        local.get $state
+        local.get $i32_local
+        i32.store offset=14
+        local.get $state
+        i32.load offset=14
+        local.get $state
+        i32.load offset=18
+        (block $b (param i32) (result i32 i32)
+            local.set $memory_address
+            local.get $utx
+            local.get $memory_address
+            i32.const 0
+            i32.add
+            i32.store
+            local.get $utx
+            i32.const 1
+            i32.store8 offset=35
+            i32.const 2
+            return
+        )
+        i32.add
+        i32.add
+        drop
+        i32.const 0
+    )
+    (func $multi_value_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $state
+        i32.load offset=14
+        (block $b (result i32 i32)
+            local.get $utx
+            i32.load
+            i32.load
+            i32.const 1
+        )
+        i32.add
+        i32.add
+        drop
+        i32.const 0
+    )
+    (table 3 funcref)
+    (elem (i32.const 1) func $multi_value $multi_value_1)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
+
+#[test]
+fn anonymous_functions() {
+    // Unnamed functions are named after their index, unless another function already has that id
+    utils::test_transform(
+        "\
+(module
+    (func (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 1
+        i32.load
+    )
+    (func $func_0 (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
+    )
+    (func (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        i32.const 0
+    )
+)",
+        "\
+(module
+    (func $anon_func_0 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        i32.const 1
+        local.set $memory_address
+        local.get $utx
+        local.get $memory_address
+        i32.const 0
+        i32.add
+        i32.store
+        local.get $utx
+        i32.const 1
+        i32.store8 offset=35
+        i32.const 2
+    )
+    (func $func_0 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        i32.const 0
+    )
+    (func $func_2 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        i32.const 0
+    )
+    (func $anon_func_0_1 (type $utx_f) (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local $memory_address i32)
+        (local $i32_local i32)
+        (local $i64_local i64)
+        (local $f32_local f32)
+        (local $f64_local f64)
+        local.get $utx
+        i32.load
+        i32.load
+    )
+    (table 5 funcref)
+    (elem (i32.const 1) func $anon_func_0 $anon_func_0_1 $func_0 $func_2)
+    (memory 10)
+    (type $utx_f (func (param i32 i32 i32) (result i32)))
+)",
+    );
+}
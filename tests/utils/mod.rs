@@ -1,10 +1,14 @@
 use pretty_assertions::assert_eq;
 
-use chop_up::transform_wat_string;
+use chop_up::{transform_wat_string, SplitOptions};
 
 pub fn test_transform(input: &str, expected_output: &str) {
+    test_transform_with_opts(input, expected_output, SplitOptions::new(6))
+}
+
+pub fn test_transform_with_opts(input: &str, expected_output: &str, options: SplitOptions) {
     let mut output_vec: Vec<u8> = Vec::new();
-    transform_wat_string(input, &mut output_vec, 6, false, false).unwrap();
+    transform_wat_string(input, &mut output_vec, &options).unwrap();
     let output_wat = String::from_utf8(output_vec).unwrap();
     assert_eq!(output_wat.trim(), expected_output.trim());
 }
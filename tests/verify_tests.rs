@@ -0,0 +1,205 @@
+use pretty_assertions::assert_eq;
+
+use chop_up::{Divergence, SkipSafe, SplitOptions, Transaction, split_wat_string, verify_output, verify_wat_string};
+
+const TRANSACTION: &str = "\
+(module
+    (type (func (param i32 i32 i32) (result i32)))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        (local i64)
+        local.get $tx
+        i32.load
+        local.get $tx
+        i64.load offset=8
+        local.set 3
+        block (result i32)
+            local.get $tx
+            i32.load16_u offset=4
+            i32.const 3
+            i32.add
+        end
+        i32.store
+        local.get $tx
+        local.get 3
+        i64.const 1
+        i64.add
+        i64.store offset=16
+        i32.const 0
+    )
+    (memory 1)
+)";
+
+fn transaction() -> Transaction {
+    let mut memory = vec![0; 4096];
+    // tx points at 2048, which holds the address written to
+    memory[1024..1028].copy_from_slice(&2048u32.to_le_bytes());
+    memory[1028] = 39;
+    memory[1032..1040].copy_from_slice(&41i64.to_le_bytes());
+    Transaction {
+        function: "transaction".into(),
+        tx: 1024,
+        utx: 3000,
+        state: 3100,
+        memory,
+    }
+}
+
+#[test]
+fn verify_equivalent_split() {
+    let verification = verify_wat_string(TRANSACTION, &transaction(), &SplitOptions::new(6)).unwrap();
+    assert_eq!(verification.original_accesses, vec![1024, 1032, 1028, 2048, 1040]);
+    assert_eq!(verification.performed_accesses, verification.original_accesses);
+    assert_eq!(verification.split_accesses, verification.original_accesses);
+    assert_eq!(verification.divergence, None);
+}
+
+#[test]
+fn verify_skip_safe() {
+    let verification = verify_wat_string(TRANSACTION, &transaction(), &SplitOptions::new(6).policy(SkipSafe)).unwrap();
+    assert_eq!(verification.original_accesses, vec![1024, 1032, 1028, 2048, 1040]);
+    // Loads through the tx pointer are skipped, stores are always split
+    assert_eq!(verification.performed_accesses, verification.original_accesses);
+    assert_eq!(verification.split_accesses, vec![2048, 1040]);
+    assert_eq!(verification.divergence, None);
+}
+
+#[test]
+fn verify_no_splits() {
+    // Without any splits the split module has no table
+    let options = SplitOptions::new(6).skip_functions(["transaction"]);
+    let output = split_wat_string(TRANSACTION, &options).unwrap();
+    assert!(!output.module.contains("(table"));
+    let verification = verify_output(TRANSACTION, &output, &transaction(), &options).unwrap();
+    assert_eq!(verification.performed_accesses, verification.original_accesses);
+    assert_eq!(verification.split_accesses, Vec::<u64>::new());
+    assert_eq!(verification.divergence, None);
+}
+
+#[test]
+fn verify_return_value() {
+    // A value returned that is no microtransaction ends the chain as the result of the transaction
+    let input = TRANSACTION
+        .replace("i32.const 0\n    )", "i32.const 1\n    )")
+        .replace("(memory 1)", "\
+(memory 1)
+    (func $__noop (param i32 i32 i32) (result i32)
+        i32.const 0
+    )
+    (table 2 funcref)
+    (elem (i32.const 1) func $__noop)");
+    let options = SplitOptions::new(6);
+    let verification = verify_wat_string(&input, &transaction(), &options).unwrap();
+    assert_eq!(verification.divergence, None);
+
+    let mut output = split_wat_string(&input, &options).unwrap();
+    output.module = output.module.replace("i32.const 1\n    )", "i32.const 0\n    )");
+    let verification = verify_output(&input, &output, &transaction(), &options).unwrap();
+    assert_eq!(verification.divergence, Some(Divergence::ReturnValue { original: 1, split: 0 }));
+}
+
+#[test]
+fn verify_call_indirect_through_existing_entry() {
+    // The table index called is loaded from memory, after the split at that load
+    let input = "\
+(module
+    (type $get_f (func (param i32) (result i32)))
+    (func $transaction (param $tx i32) (param $utx i32) (param $state i32) (result i32)
+        local.get $tx
+        local.get $tx
+        i32.load
+        call_indirect (type $get_f)
+        i32.load
+        drop
+        i32.const 0
+    )
+    (func $__first (type $get_f) (param i32) (result i32)
+        local.get 0
+        i32.const 4
+        i32.add
+    )
+    (func $__second (type $get_f) (param i32) (result i32)
+        local.get 0
+        i32.const 8
+        i32.add
+    )
+    (table 3 funcref)
+    (elem (i32.const 1) func $__first $__second)
+    (memory 1)
+)";
+    let mut transaction = transaction();
+    transaction.memory[1024..1028].copy_from_slice(&2u32.to_le_bytes());
+    let options = SplitOptions::new(6);
+    let output = split_wat_string(input, &options).unwrap();
+    // Microtransactions are added after the existing entries
+    assert_eq!(output.microtransactions[0].table_index, 3);
+    let verification = verify_output(input, &output, &transaction, &options).unwrap();
+    assert_eq!(verification.original_accesses, vec![1024, 1032]);
+    assert_eq!(verification.split_accesses, verification.original_accesses);
+    assert_eq!(verification.divergence, None);
+}
+
+#[test]
+fn verify_reports_memory_divergence() {
+    let options = SplitOptions::new(6);
+    let mut output = split_wat_string(TRANSACTION, &options).unwrap();
+    output.module = output.module.replace("i64.const 1\n", "i64.const 2\n");
+    let verification = verify_output(TRANSACTION, &output, &transaction(), &options).unwrap();
+    assert_eq!(
+        verification.divergence,
+        Some(Divergence::Memory {
+            address: 1040,
+            original: 42,
+            split: 43,
+        })
+    );
+}
+
+#[test]
+fn verify_reports_access_order_divergence() {
+    // Loading through the tx pointer in the opposite order computes the same values
+    let options = SplitOptions::new(6).policy(SkipSafe);
+    let mut output = split_wat_string(TRANSACTION, &options).unwrap();
+    output.module = output.module.replace(
+        "\
+        local.get $tx
+        i32.load
+        local.get $tx
+        i64.load offset=8
+        local.set 3
+",
+        "\
+        local.get $tx
+        i64.load offset=8
+        local.set 3
+        local.get $tx
+        i32.load
+",
+    );
+    let verification = verify_output(TRANSACTION, &output, &transaction(), &options).unwrap();
+    assert_eq!(verification.performed_accesses, vec![1032, 1024, 1028, 2048, 1040]);
+    assert_eq!(
+        verification.divergence,
+        Some(Divergence::PerformedAccess {
+            position: 0,
+            original: Some(1024),
+            split: Some(1032),
+        })
+    );
+}
+
+#[test]
+fn verify_reports_undeclared_access() {
+    // The first split yields no address, but the access it was made at is still performed after it
+    let options = SplitOptions::new(6);
+    let mut output = split_wat_string(TRANSACTION, &options).unwrap();
+    output.module = output.module.replacen("i32.const 1\n        i32.store8 offset=35", "i32.const 0\n        i32.store8 offset=35", 1);
+    let verification = verify_output(TRANSACTION, &output, &transaction(), &options).unwrap();
+    assert_eq!(verification.performed_accesses, verification.original_accesses);
+    assert_eq!(
+        verification.divergence,
+        Some(Divergence::Undeclared {
+            position: 0,
+            address: 1024,
+        })
+    );
+}